@@ -0,0 +1,213 @@
+//! Symmetric shadowcasting field of view, after Albert Ford's description.
+//! It knows nothing about tcod: the caller says which tiles let light
+//! through and gets told which tiles are seen. Symmetric means that if A
+//! can see B then B can see A, and walls are revealed without gaps.
+
+/// A slope as an exact fraction, so tiles on the edge of a shadow come out
+/// the same every time
+#[derive(Clone, Copy, Debug)]
+struct Slope {
+    num: i32,
+    den: i32,
+}
+
+impl Slope {
+    fn new(num: i32, den: i32) -> Self {
+        Slope { num, den }
+    }
+
+    /// Slope through the near edge of the tile at this depth and column
+    fn of_tile(depth: i32, col: i32) -> Self {
+        Slope::new(2 * col - 1, 2 * depth)
+    }
+}
+
+/// One row of tiles at a given distance from the origin, between two slopes
+#[derive(Clone, Copy, Debug)]
+struct Row {
+    depth: i32,
+    start: Slope,
+    end: Slope,
+}
+
+impl Row {
+    fn next(&self) -> Self {
+        Row {
+            depth: self.depth + 1,
+            ..*self
+        }
+    }
+
+    /// First and last column the row covers, rounding ties outwards
+    fn columns(&self) -> (i32, i32) {
+        // floor(depth * start + 1/2) and ceil(depth * end - 1/2)
+        let min = (2 * self.depth * self.start.num + self.start.den).div_euclid(2 * self.start.den);
+        let max = -((self.end.den - 2 * self.depth * self.end.num).div_euclid(2 * self.end.den));
+        (min, max)
+    }
+
+    /// Whether the tile's center lies inside the row's slopes, which is what
+    /// keeps floor tiles symmetric
+    fn is_symmetric(&self, col: i32) -> bool {
+        col * self.start.den >= self.depth * self.start.num
+            && col * self.end.den <= self.depth * self.end.num
+    }
+}
+
+/// The four directions the origin looks out in, each a quarter of the view
+#[derive(Clone, Copy, Debug)]
+enum Quadrant {
+    North,
+    East,
+    South,
+    West,
+}
+
+impl Quadrant {
+    fn transform(self, origin: (i32, i32), depth: i32, col: i32) -> (i32, i32) {
+        let (x, y) = origin;
+        match self {
+            Quadrant::North => (x + col, y - depth),
+            Quadrant::South => (x + col, y + depth),
+            Quadrant::East => (x + depth, y + col),
+            Quadrant::West => (x - depth, y + col),
+        }
+    }
+}
+
+/// Work out what can be seen from `origin`, up to `radius` tiles away (0 for
+/// no limit). `is_transparent` must return false outside the map, and
+/// `reveal` is called for every tile in view, walls only if `light_walls`
+pub fn compute_fov<T, R>(origin: (i32, i32), radius: i32, light_walls: bool, is_transparent: T, mut reveal: R)
+where
+    T: Fn(i32, i32) -> bool,
+    R: FnMut(i32, i32),
+{
+    reveal(origin.0, origin.1);
+    let fov = Fov {
+        origin,
+        radius,
+        light_walls,
+        is_transparent: &is_transparent,
+    };
+    for &quadrant in &[Quadrant::North, Quadrant::East, Quadrant::South, Quadrant::West] {
+        let first_row = Row {
+            depth: 1,
+            start: Slope::new(-1, 1),
+            end: Slope::new(1, 1),
+        };
+        fov.scan(quadrant, first_row, &mut reveal);
+    }
+}
+
+struct Fov<'a, T> {
+    origin: (i32, i32),
+    radius: i32,
+    light_walls: bool,
+    is_transparent: &'a T,
+}
+
+impl<'a, T: Fn(i32, i32) -> bool> Fov<'a, T> {
+    fn in_radius(&self, depth: i32, col: i32) -> bool {
+        self.radius <= 0 || depth * depth + col * col <= self.radius * self.radius
+    }
+
+    fn scan<R: FnMut(i32, i32)>(&self, quadrant: Quadrant, mut row: Row, reveal: &mut R) {
+        if self.radius > 0 && row.depth > self.radius {
+            return;
+        }
+        let mut previous_wall = None;
+        let (min_col, max_col) = row.columns();
+        for col in min_col..=max_col {
+            let (x, y) = quadrant.transform(self.origin, row.depth, col);
+            let wall = !(self.is_transparent)(x, y);
+
+            let seen = if wall { self.light_walls } else { row.is_symmetric(col) };
+            if seen && self.in_radius(row.depth, col) {
+                reveal(x, y);
+            }
+
+            match previous_wall {
+                // coming out from behind a wall narrows what is left of the row
+                Some(true) if !wall => row.start = Slope::of_tile(row.depth, col),
+                // running into a wall: look past the open stretch before it
+                Some(false) if wall => {
+                    let mut next = row.next();
+                    next.end = Slope::of_tile(row.depth, col);
+                    self.scan(quadrant, next, reveal);
+                }
+                _ => {}
+            }
+            previous_wall = Some(wall);
+        }
+        if previous_wall == Some(false) {
+            self.scan(quadrant, row.next(), reveal);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GRID: &[&str] = &[
+        "##########",
+        "#....#...#",
+        "#.##.#.#.#",
+        "#....#...#",
+        "#.#......#",
+        "#...##.#.#",
+        "#........#",
+        "##########",
+    ];
+
+    fn transparent(grid: &[&str], x: i32, y: i32) -> bool {
+        x >= 0
+            && y >= 0
+            && (y as usize) < grid.len()
+            && (x as usize) < grid[y as usize].len()
+            && grid[y as usize].as_bytes()[x as usize] != b'#'
+    }
+
+    fn seen_from(grid: &[&str], origin: (i32, i32), radius: i32, light_walls: bool) -> Vec<(i32, i32)> {
+        let mut seen = vec![];
+        compute_fov(origin, radius, light_walls, |x, y| transparent(grid, x, y), |x, y| seen.push((x, y)));
+        seen
+    }
+
+    #[test]
+    fn floor_tiles_see_each_other_both_ways() {
+        let floor: Vec<(i32, i32)> = (0..GRID.len() as i32)
+            .flat_map(|y| (0..GRID[0].len() as i32).map(move |x| (x, y)))
+            .filter(|&(x, y)| transparent(GRID, x, y))
+            .collect();
+        let views: Vec<Vec<(i32, i32)>> = floor.iter().map(|&pos| seen_from(GRID, pos, 0, true)).collect();
+        for (a, view_a) in floor.iter().zip(&views) {
+            for (b, view_b) in floor.iter().zip(&views) {
+                assert_eq!(view_a.contains(b), view_b.contains(a), "{:?} and {:?}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn walls_next_to_the_origin_are_lit() {
+        let grid = &["###", "#.#", "###"];
+        let mut lit = seen_from(grid, (1, 1), 0, true);
+        lit.sort();
+        lit.dedup();
+        assert_eq!(lit.len(), 9);
+
+        let unlit = seen_from(grid, (1, 1), 0, false);
+        assert!(unlit.iter().all(|&pos| pos == (1, 1)));
+    }
+
+    #[test]
+    fn nothing_is_seen_past_the_radius() {
+        let open = |x: i32, y: i32| x.abs() <= 20 && y.abs() <= 20;
+        let mut seen = vec![];
+        compute_fov((0, 0), 5, true, open, |x, y| seen.push((x, y)));
+        assert!(seen.iter().all(|&(x, y)| x * x + y * y <= 25));
+        assert!(seen.contains(&(5, 0)) && seen.contains(&(3, 4)));
+        assert!(!seen.contains(&(4, 4)));
+    }
+}
@@ -5,6 +5,8 @@ use tcod::input::KeyCode::*;
 use tcod::map::{FovAlgorithm, Map as FovMap};
 
 use std::cmp;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, VecDeque};
 use rand::Rng;
 use rand::prelude::*;
 use rand::distributions::{WeightedIndex};
@@ -18,11 +20,15 @@ use serde::{Deserialize, Serialize};
 
 use rodio::Sink;
 
+mod fov;
+
 const SCREEN_WIDTH: i32 = 80;
 const SCREEN_HEIGHT: i32 = 50;
 
-const MAP_WIDTH: i32 = 80;
-const MAP_HEIGHT: i32 = 43;
+// the part of the screen showing the map; levels can be bigger than this,
+// with the camera following the player
+const VIEW_WIDTH: i32 = 80;
+const VIEW_HEIGHT: i32 = 43;
 
 const SCREEN_LEVEL_WIDTH: i32 = 40;
 const CHARACTER_SCREEN_WIDTH: i32 = 30;
@@ -31,6 +37,56 @@ const ROOM_MAX_SIZE: i32 = 10;
 const ROOM_MIN_SIZE: i32 = 6;
 const MAX_ROOMS: i32 = 30;
 
+// how many maps to generate for a level before giving up on the seed
+const MAP_ATTEMPTS: i32 = 20;
+
+// prefab rooms are fitted into solid rock after the normal rooms are dug
+const PREFABS: &str = include_str!("../data/prefabs.txt");
+// every symbol a prefab template may use, blank for untouched rock
+const PREFAB_GLYPHS: &str = "#.+~:\"MI^ ";
+const MAX_PREFABS: usize = 2;
+const PREFAB_ATTEMPTS: i32 = 40;
+
+// Terrain
+
+// damage for each turn spent in lava, and on landing after a fall
+const LAVA_DAMAGE: i32 = 10;
+const CHASM_FALL_DAMAGE: i32 = 8;
+
+// fire keeps burning a creature for a few turns unless it gets into water
+const BURN_TURNS: i32 = 3;
+const BURN_DAMAGE: i32 = 3;
+
+// Walls
+
+// damage a wall takes before it gives way; fireballs wear walls down, so
+// those already dug at or smashed may cave in
+const WALL_STRENGTH: i32 = 100;
+const FIREBALL_WALL_DAMAGE: i32 = 40;
+
+// Traps
+
+const SPIKE_TRAP_DAMAGE: i32 = 10;
+const PIT_TRAP_DAMAGE: i32 = 6;
+const DART_TRAP_DAMAGE: i32 = 8;
+const GAS_TRAP_DAMAGE: i32 = 5;
+const GAS_TRAP_RADIUS: i32 = 2;
+const ALARM_NOISE_RADIUS: i32 = 30;
+
+// hidden traps close by may be noticed every turn, with a chance in percent
+// of perception times the factor; searching looks harder
+const TRAP_SPOT_RADIUS: f32 = 3.0;
+const TRAP_SPOT_FACTOR: i32 = 3;
+const SEARCH_SPOT_FACTOR: i32 = 12;
+
+// chance in percent to disarm a trap, plus bonuses for character level and
+// perception. Failing by more than the margin sets the trap off
+const DISARM_BASE_CHANCE: i32 = 30;
+const DISARM_LEVEL_BONUS: i32 = 5;
+const DISARM_PERCEPTION_BONUS: i32 = 3;
+const DISARM_FUMBLE_MARGIN: i32 = 30;
+const DISARM_XP: i32 = 10;
+
 // Items
 const HEAL_AMOUNT: i32 = 40;
 
@@ -43,38 +99,182 @@ const CONFUSE_RANGE: i32 = 8;
 const FIREBALL_DAMAGE: i32 = 25;
 const FIREBALL_RADIUS: i32 = 3;
 
+const CHARM_RANGE: i32 = 8;
+
 // Player
 
 const PLAYER: usize = 0;
 
+// Bosses
+
+// named bosses guard these depths, and the Ancient King waits on the
+// final level with the artifact
+const BOSSES: &[(u32, &str)] = &[(4, "vrimak"), (7, "scorpion queen")];
+const FINAL_LEVEL: u32 = 10;
+
+// the throne room of the Ancient Kings: @ is where the player arrives,
+// K the king, C the crown, z skeletons and s scorpion men
+const FINAL_LEVEL_LAYOUT: &[&str] = &[
+    "############################################################",
+    "#.........####################################.............#",
+    "#.........####################################.#..#..#..#..#",
+    "#...@.....####################################.............#",
+    "#........................s.........................z...K...#",
+    "#.........####################################.........C...#",
+    "#...z.....####################################.#..#..#..#..#",
+    "#.........####################################.............#",
+    "#####.##############################################.#######",
+    "#####.##############################################.#######",
+    "#####.....z.....#######.......s.......#######.....z..#######",
+    "###############.........#############.........##############",
+    "############################################################",
+];
+
 const LEVEL_UP_BASE: i32 = 200;
 const LEVEL_UP_FACTOR: i32 = 150;
 
+// Regeneration
+
+// every turn a fighter adds its constitution to its regeneration counter
+// and heals 1 HP for each full threshold reached
+const REGEN_THRESHOLD: i32 = 50;
+
 // Field of view
 
-const FOV_ALGO: FovAlgorithm = FovAlgorithm::Basic;
-const FOV_LIGHT_WALLS: bool = false;
+// the FOV algorithm and other settings are chosen in the options menu
+// and kept in this file
+const CONFIG_FILE: &str = "config.json";
+const OPTIONS_WIDTH: i32 = 40;
+
+// Light
+
+// the player sees as far as there is light to see by; tiles dimmer than
+// this stay dark even in line of sight, except right next to the player
+const MIN_LIGHT: f32 = 0.02;
+
+// a torch burns for this many turns, and warns when it is getting low
 const TORCH_RADIUS: i32 = 10;
+const TORCH_FUEL: i32 = 1000;
+const TORCH_LOW_FUEL: i32 = 100;
+
+const SCONCE_RADIUS: i32 = 7;
+const FUNGUS_RADIUS: i32 = 3;
+const FUNGUS_INTENSITY: f32 = 0.5;
+const BURNING_LIGHT_RADIUS: i32 = 3;
+const MAGIC_LIGHT_RADIUS: i32 = 2;
+const MAGIC_LIGHT_INTENSITY: f32 = 0.6;
+const LAVA_GLOW_RADIUS: i32 = 2;
+const LAVA_GLOW_INTENSITY: f32 = 0.7;
+
+// the color each kind of light tints what it falls on
+const TORCH_LIGHT: Color = Color { r: 255, g: 200, b: 130 };
+const FIRE_LIGHT: Color = Color { r: 255, g: 140, b: 60 };
+const FUNGUS_LIGHT: Color = Color { r: 140, g: 255, b: 160 };
+const MAGIC_LIGHT: Color = Color { r: 110, g: 150, b: 255 };
+const LAVA_LIGHT: Color = Color { r: 255, g: 70, b: 20 };
+
+// remembered tiles are drawn washed out and dim
+const MEMORY_DESATURATION: f32 = 0.8;
+const MEMORY_BRIGHTNESS: f32 = 0.35;
+
+// Stealth
+
+// a lit torch makes the player easier to spot, standing in the dark harder
+const STEALTH_BASE: i32 = 10;
+const TORCH_STEALTH_PENALTY: i32 = 2;
+const DARKNESS_STEALTH_BONUS: i32 = 4;
+
+const SNEAK_ATTACK_MULTIPLIER: i32 = 2;
 
-const COLOR_DARK_WALL: Color = Color { r: 0, g: 0, b: 100 };
 const COLOR_LIGHT_WALL: Color = Color {
     r: 130,
     g: 110,
     b: 50,
 };
-const COLOR_DARK_GROUND: Color = Color {
-    r: 50,
-    g: 50,
-    b: 150
-};
 const COLOR_LIGHT_GROUND: Color = Color {
     r: 200,
     g: 180,
     b: 50,
 };
+const COLOR_LIGHT_WATER: Color = Color {
+    r: 40,
+    g: 80,
+    b: 200,
+};
+const COLOR_LAVA: Color = Color {
+    r: 200,
+    g: 60,
+    b: 0,
+};
+const COLOR_CHASM: Color = Color { r: 0, g: 0, b: 0 };
 
 const LIMIT_FPS: i32 = 20;
 
+// Monster senses
+
+// chance in percent for an awake monster to notice the player in plain
+// sight, adjusted by perception against stealth and by distance
+const NOTICE_BASE_CHANCE: i32 = 50;
+const SLEEPING_MONSTER_CHANCE: f32 = 0.6;
+
+// Packs
+
+// chance that a broo leads a pack, and how many can follow it
+const PACK_CHANCE: f32 = 0.3;
+const PACK_MAX_FOLLOWERS: i32 = 3;
+
+// followers give up on flanking if it means a long way round
+const FLANK_MAX_DETOUR: i32 = 6;
+
+// Allies
+
+// following allies stay this close to the player, only fight enemies
+// within it, and come along to the next level if they are inside it
+const ALLY_FOLLOW_DISTANCE: f32 = 6.0;
+
+// Noise
+
+// how far, in steps around walls, each kind of noise carries
+const WALK_NOISE_RADIUS: i32 = 3;
+const FIGHT_NOISE_RADIUS: i32 = 8;
+const DOOR_NOISE_RADIUS: i32 = 6;
+const THUNDER_NOISE_RADIUS: i32 = 15;
+const FIREBALL_NOISE_RADIUS: i32 = 12;
+const DIG_NOISE_RADIUS: i32 = 10;
+
+// monsters that lose track of the player look around for a while
+const SEARCH_TURNS: i32 = 10;
+const SEARCH_RADIUS: i32 = 4;
+
+// Morale
+
+// badly hurt monsters with low morale run away, and seeing an ally die
+// shakes them too. Fear is the chance in percent to flee at morale 0
+const FLEE_HP_PERCENT: i32 = 30;
+const LOW_HP_FEAR: i32 = 100;
+const ALLY_DEATH_FEAR: i32 = 40;
+
+// fleeing monsters pull themselves together after healing up, or
+// after a few turns without being chased
+const RECOVER_HP_PERCENT: i32 = 60;
+const FLEE_CALM_TURNS: i32 = 8;
+
+// Ranged monsters
+
+// turns a ranged monster needs to ready its next shot
+const RANGED_COOLDOWN_TURNS: i32 = 2;
+
+// Spellcasting monsters
+
+// casters regain a point of mana every turn, and need a few turns
+// between spells
+const CASTER_MAX_MANA: i32 = 20;
+const CASTER_COOLDOWN_TURNS: i32 = 3;
+const CASTER_RANGE: f32 = 6.0;
+
+const SPELL_HEAL_AMOUNT: i32 = 15;
+const SPELL_BOLT_DAMAGE: i32 = 12;
+
 // sizes and coordinates for the GUI
 const BAR_WIDTH: i32 = 20;
 const PANEL_HEIGHT: i32 = 7;
@@ -85,6 +285,46 @@ const MSG_WIDTH: i32 = SCREEN_WIDTH - BAR_WIDTH - 2;
 const MSG_HEIGHT: usize = PANEL_HEIGHT as usize - 1;
 
 const INVENTORY_WIDTH: i32 = 50;
+const QUANTITY_WIDTH: i32 = 40;
+
+// Enchantment
+
+// chances in percent for equipment found in the dungeon to be cursed, or to
+// carry an enchantment or affix, the last two growing with depth
+const CURSE_CHANCE: i32 = 10;
+const ENCHANT_BASE_CHANCE: i32 = 20;
+const AFFIX_BASE_CHANCE: i32 = 8;
+const MAGIC_CHANCE_PER_LEVEL: i32 = 3;
+const MAX_ENCHANTMENT: i32 = 3;
+// helmets give this much max HP per point of enchantment
+const ENCHANT_HP: i32 = 5;
+// enchantments this strong make an item rare on their own
+const RARE_ENCHANTMENT: i32 = 3;
+const VAMPIRIC_PERCENT: i32 = 25;
+
+// Identification
+
+// potions and scrolls look like one of these until identified, shuffled
+// anew for every game
+const POTIONS: &[Item] = &[Item::Heal];
+const SCROLLS: &[Item] = &[
+    Item::Lightning,
+    Item::Confuse,
+    Item::Fireball,
+    Item::Summon,
+    Item::Charm,
+    Item::Identify,
+    Item::RemoveCurse,
+];
+const POTION_APPEARANCES: &[&str] = &[
+    "murky", "bubbling", "fizzy", "cloudy", "glowing", "smoky", "milky", "oily",
+    "swirling", "viscous", "sparkling", "inky",
+];
+const SCROLL_SYLLABLES: &[&str] = &[
+    "XUM", "ZELGO", "MER", "KIRJE", "ELBIB", "VERR", "YUM", "ANDOVA", "THARR",
+    "ELAM", "EBOW", "HACKEM", "MUCHE", "DAIYEN", "GARVEN", "DEH", "KERNOD",
+    "VELOX", "NEB", "TEMOV", "NEPHRAP", "FOOBIE", "ASHPD", "SOOD",
+];
 
 #[derive(Serialize, Deserialize)]
 struct Messages {
@@ -115,6 +355,121 @@ struct Tcod {
     key: Key,
     mouse: Mouse,
     sink: rodio::Sink,
+    // map position of the top left corner of the view
+    camera: (i32, i32),
+    // set when a tile changes, so the player's FOV gets recomputed
+    fov_stale: bool,
+    // scratch FOV map for working out what each light source reaches
+    light_fov: FovMap,
+    // red, green and blue light falling on each tile, and which tiles
+    // the player can see
+    light: Vec<Vec<[f32; 3]>>,
+    visible: Vec<Vec<bool>>,
+    options: Options,
+}
+
+/// Settings the player picks in the options menu, saved between games
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+struct Options {
+    fov: FovSetting,
+    // show the walls bounding what the player sees
+    light_walls: bool,
+    // auto-explore picks up items it comes across instead of stopping
+    auto_explore_pickup: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            fov: FovSetting::Symmetric,
+            light_walls: true,
+            auto_explore_pickup: true,
+        }
+    }
+}
+
+/// The ways of working out what can be seen: libtcod's algorithms, or our
+/// own symmetric shadowcasting
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum FovSetting {
+    Basic,
+    Diamond,
+    Shadow,
+    Permissive(u8),
+    Restrictive,
+    Symmetric,
+}
+
+impl FovSetting {
+    const ALL: [FovSetting; 14] = [
+        FovSetting::Basic,
+        FovSetting::Diamond,
+        FovSetting::Shadow,
+        FovSetting::Permissive(0),
+        FovSetting::Permissive(1),
+        FovSetting::Permissive(2),
+        FovSetting::Permissive(3),
+        FovSetting::Permissive(4),
+        FovSetting::Permissive(5),
+        FovSetting::Permissive(6),
+        FovSetting::Permissive(7),
+        FovSetting::Permissive(8),
+        FovSetting::Restrictive,
+        FovSetting::Symmetric,
+    ];
+
+    /// The libtcod algorithm to use, or None for our own
+    fn algorithm(self) -> Option<FovAlgorithm> {
+        use FovSetting::*;
+        match self {
+            Basic => Some(FovAlgorithm::Basic),
+            Diamond => Some(FovAlgorithm::Diamond),
+            Shadow => Some(FovAlgorithm::Shadow),
+            Permissive(0) => Some(FovAlgorithm::Permissive0),
+            Permissive(1) => Some(FovAlgorithm::Permissive1),
+            Permissive(2) => Some(FovAlgorithm::Permissive2),
+            Permissive(3) => Some(FovAlgorithm::Permissive3),
+            Permissive(4) => Some(FovAlgorithm::Permissive4),
+            Permissive(5) => Some(FovAlgorithm::Permissive5),
+            Permissive(6) => Some(FovAlgorithm::Permissive6),
+            Permissive(7) => Some(FovAlgorithm::Permissive7),
+            Permissive(_) => Some(FovAlgorithm::Permissive8),
+            Restrictive => Some(FovAlgorithm::Restrictive),
+            Symmetric => None,
+        }
+    }
+}
+
+impl std::fmt::Display for FovSetting {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FovSetting::Basic => write!(f, "basic"),
+            FovSetting::Diamond => write!(f, "diamond"),
+            FovSetting::Shadow => write!(f, "shadow casting"),
+            FovSetting::Permissive(level) => write!(f, "permissive {}", level),
+            FovSetting::Restrictive => write!(f, "restrictive"),
+            FovSetting::Symmetric => write!(f, "symmetric shadow casting"),
+        }
+    }
+}
+
+impl Tcod {
+    /// True if the player can see the position: in line of sight and lit
+    pub fn is_visible(&self, x: i32, y: i32) -> bool {
+        self.visible
+            .get(x as usize)
+            .and_then(|column| column.get(y as usize))
+            .cloned()
+            .unwrap_or(false)
+    }
+
+    pub fn is_lit(&self, x: i32, y: i32) -> bool {
+        self.light
+            .get(x as usize)
+            .and_then(|column| column.get(y as usize))
+            .map_or(false, |&light| brightness(light) >= MIN_LIGHT)
+    }
 }
 
 // Tiles
@@ -123,22 +478,119 @@ struct Tile {
     blocked: bool,
     block_sight: bool,
     explored: bool,
+    #[serde(default)]
+    terrain: Terrain,
+    // how much a wall has been dug at, smashed or burned
+    #[serde(default)]
+    damage: i32,
+}
+
+/// What a tile is made of
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+enum Terrain {
+    Wall,
+    #[default]
+    Floor,
+    Door,
+    Water,
+    Lava,
+    Rubble,
+    Chasm,
+    Grass,
+    Bridge,
+}
+
+impl Terrain {
+    /// Turns it takes to walk onto a tile of this terrain
+    pub fn move_cost(self) -> i32 {
+        match self {
+            Terrain::Water | Terrain::Rubble => 2,
+            _ => 1,
+        }
+    }
+
+    /// Creatures keep out of terrain that would hurt them
+    pub fn is_hazard(self) -> bool {
+        self == Terrain::Lava || self == Terrain::Chasm
+    }
 }
 
 impl Tile {
-    pub fn empty() -> Self {
+    fn new(terrain: Terrain, blocked: bool, block_sight: bool) -> Self {
         Tile {
-            blocked: false,
-            block_sight: false,
+            blocked: blocked,
+            block_sight: block_sight,
             explored: false,
+            terrain: terrain,
+            damage: 0,
         }
     }
 
+    pub fn empty() -> Self {
+        Tile::new(Terrain::Floor, false, false)
+    }
+
     pub fn wall() -> Self {
-        Tile {
-            blocked: true,
-            block_sight: true,
-            explored: false,
+        Tile::new(Terrain::Wall, true, true)
+    }
+
+    /// A closed door, which opens when walked into
+    pub fn door() -> Self {
+        Tile::new(Terrain::Door, true, true)
+    }
+
+    /// Shallow water, slow to wade through
+    pub fn water() -> Self {
+        Tile::new(Terrain::Water, false, false)
+    }
+
+    pub fn lava() -> Self {
+        Tile::new(Terrain::Lava, false, false)
+    }
+
+    /// Fallen stone, slow to climb over
+    pub fn rubble() -> Self {
+        Tile::new(Terrain::Rubble, false, false)
+    }
+
+    /// A drop to the level below
+    pub fn chasm() -> Self {
+        Tile::new(Terrain::Chasm, false, false)
+    }
+
+    /// Tall grass hides what is behind it until it is trampled
+    pub fn grass(tall: bool) -> Self {
+        Tile::new(Terrain::Grass, false, tall)
+    }
+
+    pub fn bridge() -> Self {
+        Tile::new(Terrain::Bridge, false, false)
+    }
+
+    /// Glyph drawn on the tile, if any
+    pub fn glyph(&self) -> Option<(char, Color)> {
+        match self.terrain {
+            Terrain::Door if self.blocked => Some(('+', LIGHT_SEPIA)),
+            Terrain::Door => Some(('\'', LIGHT_SEPIA)),
+            Terrain::Water => Some(('~', LIGHT_BLUE)),
+            Terrain::Lava => Some(('~', YELLOW)),
+            Terrain::Rubble => Some((':', LIGHT_GREY)),
+            Terrain::Grass if self.block_sight => Some(('"', DARK_GREEN)),
+            Terrain::Grass => Some(('"', GREEN)),
+            Terrain::Bridge => Some(('=', SEPIA)),
+            Terrain::Wall | Terrain::Floor | Terrain::Chasm => None,
+        }
+    }
+
+    /// Background color of the tile in full white light
+    pub fn background(&self) -> Color {
+        match self.terrain {
+            Terrain::Wall => COLOR_LIGHT_WALL,
+            Terrain::Door if self.blocked => COLOR_LIGHT_WALL,
+            Terrain::Water => COLOR_LIGHT_WATER,
+            Terrain::Lava => COLOR_LAVA,
+            Terrain::Chasm => COLOR_CHASM,
+            _ => COLOR_LIGHT_GROUND,
         }
     }
 }
@@ -175,6 +627,11 @@ impl Rect {
             && (self.y1 <= other.y2)
             && (self.y2 >= other.y1)
     } 
+
+    /// True if the position lies inside the rectangle or on its edge
+    pub fn contains(&self, x: i32, y: i32) -> bool {
+        x >= self.x1 && x <= self.x2 && y >= self.y1 && y <= self.y2
+    }
 }
 
 // Dungeon creation functions
@@ -204,12 +661,160 @@ fn create_v_tunnel(y1: i32, y2: i32, x: i32, map: &mut Map) {
 // Map
 type Map = Vec<Vec<Tile>>;
 
+/// Width and height of a map in tiles
+fn map_size(map: &Map) -> (i32, i32) {
+    (map.len() as i32, map.first().map_or(0, |column| column.len()) as i32)
+}
+
+fn in_map(x: i32, y: i32, map: &Map) -> bool {
+    let (width, height) = map_size(map);
+    x >= 0 && y >= 0 && x < width && y < height
+}
+
+/// Levels grow as the player goes deeper
+fn level_size(level: u32) -> (i32, i32) {
+    match level {
+        1 | 2 => (VIEW_WIDTH, VIEW_HEIGHT),
+        3..=5 => (100, 55),
+        _ => (120, 65),
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 struct Game {
     map: Map,
     messages: Messages,
     inventory: Vec<Object>,
     dungeon_level: u32,
+    #[serde(skip)]
+    activity: Option<Activity>,
+    // where monsters died this turn, to shake the morale of onlookers
+    #[serde(skip)]
+    recent_deaths: Vec<(i32, i32)>,
+    #[serde(default)]
+    factions: FactionTable,
+    // what unidentified potions and scrolls look like in this game
+    #[serde(default)]
+    identification: Identification,
+    // monsters that fell into a chasm this turn, removed once all have moved
+    #[serde(skip)]
+    fallen: Vec<usize>,
+    // the levels the player has left, as they were left
+    #[serde(default)]
+    visited: Vec<Level>,
+    // every level is generated from this, so a game can be played again
+    #[serde(default)]
+    seed: u64,
+}
+
+/// A level the player is not on at the moment
+#[derive(Serialize, Deserialize)]
+struct Level {
+    depth: u32,
+    map: Map,
+    objects: Vec<Object>,
+}
+
+/// The random looks of potions and scrolls in this game, and which kinds
+/// the player has learnt to recognise
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+struct Identification {
+    appearances: Vec<(Item, String)>,
+    known: Vec<Item>,
+}
+
+impl Identification {
+    pub fn new<R: Rng>(rng: &mut R) -> Self {
+        let mut potions: Vec<_> = POTION_APPEARANCES.to_vec();
+        potions.shuffle(rng);
+        let mut appearances: Vec<(Item, String)> = POTIONS
+            .iter()
+            .zip(potions)
+            .map(|(&item, look)| (item, format!("{} potion", look)))
+            .collect();
+
+        let mut labels: Vec<String> = vec![];
+        while labels.len() < SCROLLS.len() {
+            let words = rng.gen_range(1, 3);
+            let label = (0..words)
+                .map(|_| *SCROLL_SYLLABLES.choose(rng).unwrap())
+                .collect::<Vec<_>>()
+                .join(" ");
+            if !labels.contains(&label) {
+                labels.push(label);
+            }
+        }
+        appearances.extend(
+            SCROLLS
+                .iter()
+                .zip(labels)
+                .map(|(&item, label)| (item, format!("scroll labelled {}", label))),
+        );
+
+        Identification { appearances, known: vec![] }
+    }
+
+    /// What an unidentified kind of item looks like, if it is still unknown
+    pub fn appearance(&self, item: Item) -> Option<&str> {
+        if self.known.contains(&item) {
+            return None;
+        }
+        self.appearances
+            .iter()
+            .find(|(kind, _)| *kind == item)
+            .map(|(_, look)| look.as_str())
+    }
+
+    pub fn is_known(&self, item: Item) -> bool {
+        self.appearance(item).is_none()
+    }
+
+    /// Learn a kind of item, returning true if it wasn't known before
+    pub fn identify(&mut self, item: Item) -> bool {
+        if self.is_known(item) {
+            return false;
+        }
+        self.known.push(item);
+        true
+    }
+
+    /// The name the player knows an object by
+    pub fn name_of(&self, object: &Object) -> String {
+        object
+            .item
+            .and_then(|item| self.appearance(item))
+            .map_or_else(|| object.known_name(), |look| look.to_string())
+    }
+
+    /// The name with how many there are: "a healing potion", "3 healing potions"
+    pub fn describe(&self, object: &Object) -> String {
+        let name = self.name_of(object);
+        if object.quantity > 1 {
+            format!("{} {}", object.quantity, plural(&name))
+        } else {
+            format!("a {}", name)
+        }
+    }
+}
+
+/// A multi-turn action the player keeps repeating until it is finished
+/// or interrupted
+#[derive(Clone, Debug, PartialEq)]
+enum Activity {
+    Rest,
+    Explore {
+        // positions of items already noticed, so they don't stop us again
+        known_items: Vec<(i32, i32)>,
+    },
+    Travel {
+        x: i32,
+        y: i32,
+    },
+    // digging out the wall at a position
+    Dig {
+        x: i32,
+        y: i32,
+    },
 }
 
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -217,6 +822,15 @@ enum PlayerAction {
     TookTurn,
     DidntTaketurn,
     Exit,
+    Victory,
+}
+
+/// Like `is_blocked`, but monsters push closed doors open as they walk
+/// into them
+fn monster_can_enter(x: i32, y: i32, map: &Map, objects: &[Object]) -> bool {
+    let tile = &map[x as usize][y as usize];
+    (!tile.blocked || tile.terrain == Terrain::Door)
+        && !objects.iter().any(|object| object.blocks && object.pos() == (x, y))
 }
 
 fn is_blocked(x: i32, y: i32, map: &Map, objects: &[Object]) -> bool {
@@ -230,25 +844,200 @@ fn is_blocked(x: i32, y: i32, map: &Map, objects: &[Object]) -> bool {
         .any(|object| object.blocks && object.pos() == (x, y))
 }
 
-fn make_map(objects: &mut Vec<Object>, level: u32) -> Map {
+/// Generate the map for a level from a seed. Maps that leave the stairs or
+/// an item out of reach get a tunnel dug to them, and are thrown away for
+/// another try if that doesn't help. If no try works out, the one with the
+/// fewest things out of reach is used
+fn make_map(objects: &mut Vec<Object>, level: u32, seed: u64) -> Map {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut best: Option<(usize, Map, Vec<Object>)> = None;
+    for _ in 0..MAP_ATTEMPTS {
+        let mut map = generate_map(objects, level, &mut rng);
+        let unreachable = unreachable_positions(&map, objects);
+        if unreachable.is_empty() {
+            return map;
+        }
+        repair_map(&mut map, objects, &unreachable);
+        let unreachable = unreachable_positions(&map, objects).len();
+        if unreachable == 0 {
+            return map;
+        }
+        if best.as_ref().map_or(true, |&(fewest, _, _)| unreachable < fewest) {
+            best = Some((unreachable, map, objects.clone()));
+        }
+    }
+
+    let (_, map, best_objects) = best.expect("no map attempts");
+    *objects = best_objects;
+    debug_assert!(
+        unreachable_positions(&map, objects).is_empty(),
+        "no playable map for level {} with seed {}",
+        level,
+        seed
+    );
+    map
+}
+
+/// Each level gets its own seed, worked out from the game's. The bits are
+/// mixed (a SplitMix64 step) so that nearby games don't share levels
+fn level_seed(game_seed: u64, level: u32) -> u64 {
+    let mut z = (game_seed ^ u64::from(level)).wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// Positions of the stairs and items the player can't walk to, treating
+/// closed doors as open and keeping out of lava and chasms
+fn unreachable_positions(map: &Map, objects: &[Object]) -> Vec<(i32, i32)> {
+    let reached = reachable_tiles(objects[PLAYER].pos(), map);
+    objects
+        .iter()
+        .filter(|o| o.item.is_some() || o.name == "stairs" || o.name == "up stairs")
+        .map(|o| o.pos())
+        .filter(|&(x, y)| !reached[x as usize][y as usize])
+        .collect()
+}
+
+/// Flood fill the tiles that can be walked to from a position
+fn reachable_tiles(start: (i32, i32), map: &Map) -> Vec<Vec<bool>> {
+    let (width, height) = map_size(map);
+    let mut reached = vec![vec![false; height as usize]; width as usize];
+    reached[start.0 as usize][start.1 as usize] = true;
+    let mut frontier = vec![start];
+    while let Some((x, y)) = frontier.pop() {
+        for dx in -1..=1 {
+            for dy in -1..=1 {
+                let (nx, ny) = (x + dx, y + dy);
+                if !in_map(nx, ny, map) {
+                    continue;
+                }
+                let tile = &map[nx as usize][ny as usize];
+                let passable = !tile.blocked || tile.terrain == Terrain::Door;
+                if reached[nx as usize][ny as usize] || !passable || tile.terrain.is_hazard() {
+                    continue;
+                }
+                reached[nx as usize][ny as usize] = true;
+                frontier.push((nx, ny));
+            }
+        }
+    }
+    reached
+}
+
+/// Dig the shortest tunnel from each cut off position to the player's part
+/// of the map
+fn repair_map(map: &mut Map, objects: &[Object], unreachable: &[(i32, i32)]) {
+    let (width, height) = map_size(map);
+    for &(x, y) in unreachable {
+        let reached = reachable_tiles(objects[PLAYER].pos(), map);
+        if reached[x as usize][y as usize] {
+            // an earlier tunnel got here already
+            continue;
+        }
+        let path = find_path(
+            (x, y),
+            map,
+            |nx, ny| nx > 0 && ny > 0 && nx < width - 1 && ny < height - 1,
+            |nx, ny| reached[nx as usize][ny as usize],
+        );
+        if let Some(path) = path {
+            for (nx, ny) in path {
+                let tile = &mut map[nx as usize][ny as usize];
+                let wall = tile.blocked && tile.terrain != Terrain::Door;
+                if wall || tile.terrain.is_hazard() {
+                    *tile = Tile::empty();
+                }
+            }
+        }
+    }
+}
+
+/// Give a room a feature: a pool, grass, rubble, or deeper down a lava
+/// pool or a chasm with a bridge. Hazards leave a way around or across,
+/// and lava is only poured where it won't cut off anything in the room
+fn decorate_room(room: Rect, map: &mut Map, objects: &[Object], level: u32, rng: &mut StdRng) {
+    let feature_chances = [
+        (Terrain::Floor, 50),
+        (Terrain::Water, 15),
+        (Terrain::Grass, 15),
+        (Terrain::Rubble, 10),
+        (Terrain::Lava, from_dungeon_level(&[Transition { level: 4, value: 8 }], level)),
+        (Terrain::Chasm, from_dungeon_level(&[Transition { level: 3, value: 6 }], level)),
+    ];
+    let dist = WeightedIndex::new(feature_chances.iter().map(|feature| feature.1)).unwrap();
+    let feature = feature_chances[dist.sample(rng)].0;
+
+    let (center_x, center_y) = room.center();
+    let occupied = |x: i32, y: i32| objects.iter().any(|o| o.pos() == (x, y));
+    // an ellipse inside the room, leaving a walkway of `margin` tiles
+    let in_pool = |x: i32, y: i32, margin: i32| {
+        let radius_x = ((room.x2 - room.x1) / 2 - margin) as f32;
+        let radius_y = ((room.y2 - room.y1) / 2 - margin) as f32;
+        if radius_x < 1.0 || radius_y < 1.0 {
+            return false;
+        }
+        let dx = (x - center_x) as f32 / radius_x;
+        let dy = (y - center_y) as f32 / radius_y;
+        dx * dx + dy * dy <= 1.0
+    };
+
+    let lava_covers_something = objects.iter().any(|o| in_pool(o.x, o.y, 1));
+    if feature == Terrain::Lava && lava_covers_something {
+        return;
+    }
+
+    for x in (room.x1 + 1)..room.x2 {
+        for y in (room.y1 + 1)..room.y2 {
+            let tile = match feature {
+                Terrain::Water if in_pool(x, y, 1) => Tile::water(),
+                Terrain::Lava if in_pool(x, y, 2) => Tile::lava(),
+                Terrain::Grass if rng.gen_range(0, 100) < 60 => Tile::grass(rng.gen()),
+                Terrain::Rubble if rng.gen_range(0, 100) < 20 => Tile::rubble(),
+                // a chasm across the room, with a bridge on the middle row
+                // where tunnels come in
+                Terrain::Chasm if x == center_x - 2 && x > room.x1 + 1 => {
+                    if y == center_y {
+                        Tile::bridge()
+                    } else if occupied(x, y) {
+                        continue;
+                    } else {
+                        Tile::chasm()
+                    }
+                }
+                _ => continue,
+            };
+            map[x as usize][y as usize] = tile;
+        }
+    }
+}
+
+fn generate_map(objects: &mut Vec<Object>, level: u32, rng: &mut StdRng) -> Map {
 
     // fill with blocked tiles
-    let mut map = vec![vec![Tile::wall(); MAP_HEIGHT as usize]; MAP_WIDTH as usize];
+    let (width, height) = level_size(level);
+    let mut map = vec![vec![Tile::wall(); height as usize]; width as usize];
 
     // Player is the first element, remove everything else
     // NOTE: only works when the player is the first object
     assert_eq!(&objects[PLAYER] as *const _, &objects[0] as *const _);
     objects.truncate(1);
+
+    if level == FINAL_LEVEL {
+        return make_final_level(objects);
+    }
     
     let mut rooms = vec![];
 
-    for _ in 0..MAX_ROOMS {
+    // bigger levels get more rooms
+    let max_rooms = MAX_ROOMS * width * height / (VIEW_WIDTH * VIEW_HEIGHT);
+    for _ in 0..max_rooms {
         // random width and height
-        let w = rand::thread_rng().gen_range(ROOM_MIN_SIZE, ROOM_MAX_SIZE + 1);
-        let h = rand::thread_rng().gen_range(ROOM_MIN_SIZE, ROOM_MAX_SIZE + 1);
+        let w = rng.gen_range(ROOM_MIN_SIZE, ROOM_MAX_SIZE + 1);
+        let h = rng.gen_range(ROOM_MIN_SIZE, ROOM_MAX_SIZE + 1);
         // random position without going beyond map boundaries
-        let x = rand::thread_rng().gen_range(0, MAP_WIDTH - w);
-        let y = rand::thread_rng().gen_range(0, MAP_HEIGHT - h);
+        let x = rng.gen_range(0, width - w);
+        let y = rng.gen_range(0, height - h);
 
         let new_room = Rect::new(x, y, w, h);
 
@@ -261,7 +1050,7 @@ fn make_map(objects: &mut Vec<Object>, level: u32) -> Map {
             // means so intersections, so the room is valid
             create_room(new_room, &mut map);
 
-            place_objects(new_room, &map, objects, level);
+            place_objects(new_room, &map, objects, level, rng);
 
             let (new_x, new_y) = new_room.center();
 
@@ -274,7 +1063,7 @@ fn make_map(objects: &mut Vec<Object>, level: u32) -> Map {
                 // centre coordinates of previous room
                 let (prev_x, prev_y) = rooms[rooms.len() - 1].center();
 
-                if rand::random() {
+                if rng.gen() {
                     // first move horizontally, then vertically
                     create_h_tunnel(prev_x, new_x, prev_y, &mut map);
                     create_v_tunnel(prev_y, new_y, new_x, &mut map);
@@ -290,13 +1079,14 @@ fn make_map(objects: &mut Vec<Object>, level: u32) -> Map {
     }
 
 
-    let room1 = Rect::new(20, 15, 10, 15);
-    let room2 = Rect::new(50, 15, 10, 15);
-
-    create_room(room1, &mut map);
-    create_room(room2, &mut map);
+    // the player's first room and the one with the stairs are left plain
+    if rooms.len() > 2 {
+        for &room in &rooms[1..rooms.len() - 1] {
+            decorate_room(room, &mut map, objects, level, rng);
+        }
+    }
 
-    create_h_tunnel(25, 55, 23, &mut map);
+    place_prefabs(&mut map, objects, level, rng);
 
     // create stairs at the center of the last room
     let (last_room_x, last_room_y) = rooms[rooms.len() - 1].center();
@@ -304,67 +1094,522 @@ fn make_map(objects: &mut Vec<Object>, level: u32) -> Map {
     stairs.always_visible = true;
     objects.push(stairs);
 
-    //map[30][22] = Tile::wall();
-    //map[50][22] = Tile::wall();
+    // and the way back up where the player arrives
+    let (player_x, player_y) = objects[PLAYER].pos();
+    objects.push(up_stairs(player_x, player_y));
+
+    // a boss waits by the stairs down on its level
+    if let Some(&(_, boss)) = BOSSES.iter().find(|&&(depth, _)| depth == level) {
+        if let Some((x, y)) = free_spot_near(last_room_x, last_room_y, &map, objects) {
+            objects.push(create_monster(boss, x, y));
+        }
+    }
 
     map
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-struct Object {
-    x: i32,
-    y: i32,
-    glyph: char,
-    color: Color,
+/// A hand-authored room template
+struct Prefab {
     name: String,
-    blocks: bool,
-    alive: bool,
-    fighter: Option<Fighter>,
-    ai: Option<AI>,
-    item: Option<Item>,
-    always_visible: bool,
-    level: i32,
-    equipment: Option<Equipment>,
+    min_level: u32,
+    rows: Vec<Vec<char>>,
 }
 
-impl Object {
-    pub fn new(x: i32, y: i32, glyph: char, color: Color, name: String, blocks: bool) -> Self {
-        Object {
-            x: x,
-            y: y,
-            glyph: glyph,
-            color: color,
-            name: name,
-            blocks: blocks,
-            alive: false,
-            fighter: None,
-            ai: None,
-            item: None,
-            always_visible: false,
-            level: 1,
-            equipment: None,
+/// Parse the prefab templates from the data file
+fn load_prefabs() -> Vec<Prefab> {
+    let mut prefabs: Vec<Prefab> = vec![];
+    for line in PREFABS.lines() {
+        if line.starts_with(';') {
+            continue;
+        }
+        if let Some(header) = line.strip_prefix('=') {
+            let mut header = header.split_whitespace().collect::<Vec<_>>();
+            let min_level = header.pop().and_then(|l| l.parse().ok()).expect("prefab without a level");
+            prefabs.push(Prefab {
+                name: header.join(" "),
+                min_level,
+                rows: vec![],
+            });
+        } else if !line.trim().is_empty() {
+            let prefab = prefabs.last_mut().expect("prefab rows before a header");
+            if let Some(glyph) = line.chars().find(|&glyph| !PREFAB_GLYPHS.contains(glyph)) {
+                panic!("prefab {} has an unknown symbol '{}'", prefab.name, glyph);
+            }
+            prefab.rows.push(line.chars().collect());
         }
     }
 
-    pub fn draw(&self, con: &mut dyn Console) {
-        con.set_default_foreground(self.color);
-        con.put_char(self.x, self.y, self.glyph, BackgroundFlag::None);
+    // pad ragged rows out with spaces, which leave the map untouched
+    for prefab in &mut prefabs {
+        let width = prefab.rows.iter().map(|row| row.len()).max().unwrap_or(0);
+        for row in &mut prefab.rows {
+            row.resize(width, ' ');
+        }
+        debug_assert!(!prefab_doors(&prefab.rows).is_empty(), "prefab {} has no way in", prefab.name);
     }
+    prefabs
+}
 
-    pub fn pos(&self) -> (i32, i32) {
-        (self.x, self.y)
+/// Rotate a template clockwise a number of quarter turns, then mirror it
+fn transform_prefab(rows: &[Vec<char>], rotation: u32, mirror: bool) -> Vec<Vec<char>> {
+    let mut rows = rows.to_vec();
+    for _ in 0..rotation % 4 {
+        let height = rows.len();
+        let width = rows[0].len();
+        rows = (0..width)
+            .map(|x| (0..height).rev().map(|y| rows[y][x]).collect())
+            .collect();
+    }
+    if mirror {
+        for row in &mut rows {
+            row.reverse();
+        }
     }
+    rows
+}
 
-    pub fn set_pos(&mut self, x: i32, y: i32) {
-        self.x = x;
-        self.y = y;
+/// Doors on the outer edge of a template, with the direction leading out
+fn prefab_doors(rows: &[Vec<char>]) -> Vec<((i32, i32), (i32, i32))> {
+    let height = rows.len() as i32;
+    let width = rows.first().map_or(0, |row| row.len()) as i32;
+    let mut doors = vec![];
+    for (y, row) in rows.iter().enumerate() {
+        for (x, &glyph) in row.iter().enumerate() {
+            let (x, y) = (x as i32, y as i32);
+            if glyph != '+' {
+                continue;
+            }
+            let out = if y == 0 {
+                (0, -1)
+            } else if y == height - 1 {
+                (0, 1)
+            } else if x == 0 {
+                (-1, 0)
+            } else if x == width - 1 {
+                (1, 0)
+            } else {
+                continue;
+            };
+            doors.push(((x, y), out));
+        }
     }
+    doors
+}
 
-    /// return the distance to another object
-    pub fn distance_to(&self, other: &Object) -> f32 {
-        let dx = other.x - self.x;
-        let dy = other.y - self.y;
-        ((dx.pow(2) + dy.pow(2)) as f32).sqrt()
+/// Fit a few prefab rooms into untouched rock and dig a tunnel from each
+/// of their outer doors to the rest of the level
+fn place_prefabs(map: &mut Map, objects: &mut Vec<Object>, level: u32, rng: &mut StdRng) {
+    let prefabs: Vec<Prefab> = load_prefabs()
+        .into_iter()
+        .filter(|prefab| prefab.min_level <= level)
+        .collect();
+    if prefabs.is_empty() {
+        return;
+    }
+
+    // the ground each stamped prefab covers, walls included
+    let mut placed: Vec<Rect> = vec![];
+    for _ in 0..PREFAB_ATTEMPTS {
+        if placed.len() >= MAX_PREFABS {
+            break;
+        }
+        let prefab = &prefabs[rng.gen_range(0, prefabs.len())];
+        let rows = transform_prefab(&prefab.rows, rng.gen_range(0, 4), rng.gen());
+        let height = rows.len() as i32;
+        let width = rows[0].len() as i32;
+        let (map_width, map_height) = map_size(map);
+        if width + 4 >= map_width || height + 4 >= map_height {
+            continue;
+        }
+
+        // keep a margin of rock all around so it doesn't break into anything,
+        // and stay clear of the map edge so every door can be dug out to
+        let left = rng.gen_range(2, map_width - width - 2);
+        let top = rng.gen_range(2, map_height - height - 2);
+        let solid = (left - 1..left + width + 1)
+            .all(|x| (top - 1..top + height + 1).all(|y| map[x as usize][y as usize].blocked));
+        let margin = Rect::new(left - 1, top - 1, width + 1, height + 1);
+        if !solid || placed.iter().any(|other| other.intersects_with(&margin)) {
+            continue;
+        }
+
+        stamp_prefab(&rows, left, top, map, objects, level, rng);
+        placed.push(Rect::new(left, top, width - 1, height - 1));
+        // tunnels go around prefabs, never through their walls
+        let inside = |x: i32, y: i32| placed.iter().any(|prefab| prefab.contains(x, y));
+        for ((door_x, door_y), (dx, dy)) in prefab_doors(&rows) {
+            let start = (left + door_x + dx, top + door_y + dy);
+            let path = find_path(
+                start,
+                map,
+                |x, y| !inside(x, y) && x > 0 && y > 0 && x < map_width - 1 && y < map_height - 1,
+                |x, y| !inside(x, y) && !map[x as usize][y as usize].blocked,
+            );
+            if let Some(path) = path {
+                for (x, y) in std::iter::once(start).chain(path) {
+                    map[x as usize][y as usize] = Tile::empty();
+                }
+            }
+        }
+    }
+}
+
+/// Copy a template into the map with its top left corner at a position
+fn stamp_prefab(
+    rows: &[Vec<char>],
+    left: i32,
+    top: i32,
+    map: &mut Map,
+    objects: &mut Vec<Object>,
+    level: u32,
+    rng: &mut StdRng,
+) {
+    for (row, line) in rows.iter().enumerate() {
+        for (column, &glyph) in line.iter().enumerate() {
+            let x = left + column as i32;
+            let y = top + row as i32;
+            let tile = match glyph {
+                '#' => Tile::wall(),
+                '+' => Tile::door(),
+                '~' => Tile::water(),
+                ':' => Tile::rubble(),
+                '"' => Tile::grass(true),
+                ' ' => continue,
+                // floor, with whatever stands on it
+                _ => Tile::empty(),
+            };
+            map[x as usize][y as usize] = tile;
+            match glyph {
+                'M' => objects.push(create_monster(random_monster(level, rng), x, y)),
+                'I' => objects.push(create_item(random_item(level, rng), x, y, level)),
+                '^' => objects.push(create_trap(random_trap(level, rng), x, y)),
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Create a hidden trap. The Broo laid it, so only their faction knows it is there
+fn create_trap(trap: Trap, x: i32, y: i32) -> Object {
+    let (name, color) = match trap {
+        Trap::Spikes => ("spike trap", LIGHT_GREY),
+        Trap::Pit => ("pit trap", DARK_SEPIA),
+        Trap::Dart => ("dart trap", LIGHT_GREY),
+        Trap::PoisonGas => ("gas trap", LIGHT_GREEN),
+        Trap::Teleport => ("teleport trap", LIGHT_MAGENTA),
+        Trap::Alarm => ("alarm trap", LIGHT_YELLOW),
+    };
+    let mut object = Object::new(x, y, '^', color, name.to_string(), false);
+    object.trap = Some(trap);
+    object.faction = Faction::Chaos;
+    object
+}
+
+/// Pick a random kind of trap for the level
+fn random_trap(level: u32, rng: &mut StdRng) -> Trap {
+    let trap_chances = [
+        (Trap::Spikes, 30),
+        (Trap::Pit, 30),
+        (Trap::Dart, from_dungeon_level(&[Transition { level: 2, value: 20 }], level)),
+        (Trap::Alarm, from_dungeon_level(&[Transition { level: 2, value: 15 }], level)),
+        (Trap::PoisonGas, from_dungeon_level(&[Transition { level: 4, value: 15 }], level)),
+        (Trap::Teleport, from_dungeon_level(&[Transition { level: 5, value: 10 }], level)),
+    ];
+    let dist = WeightedIndex::new(trap_chances.iter().map(|trap| trap.1)).unwrap();
+    trap_chances[dist.sample(rng)].0
+}
+
+/// Traps that have not been found yet are not drawn
+fn is_hidden_trap(object: &Object) -> bool {
+    object.trap.is_some() && !object.always_visible
+}
+
+/// A faction knows about the traps it laid itself, and the player's side
+/// knows about the ones that have been found
+fn knows_trap(faction: Faction, trap: &Object) -> bool {
+    trap.faction == faction || (faction == Faction::Player && trap.always_visible)
+}
+
+/// True if there is a trap at the position that the faction knows to avoid
+fn known_trap_at(x: i32, y: i32, faction: Faction, objects: &[Object]) -> bool {
+    objects
+        .iter()
+        .any(|o| o.trap.is_some() && o.pos() == (x, y) && knows_trap(faction, o))
+}
+
+/// True if members of the faction would rather not step on the position:
+/// lava, chasms and traps they know about
+fn is_hazard(x: i32, y: i32, faction: Faction, map: &Map, objects: &[Object]) -> bool {
+    map[x as usize][y as usize].terrain.is_hazard() || known_trap_at(x, y, faction, objects)
+}
+
+fn trap_at(x: i32, y: i32, objects: &[Object]) -> Option<usize> {
+    objects.iter().position(|o| o.trap.is_some() && o.pos() == (x, y))
+}
+
+/// Set off a trap under whoever stepped onto it. The trap is found for good
+/// if the player sees it go off
+fn spring_trap(trap_id: usize, victim_id: usize, tcod: &Tcod, game: &mut Game, objects: &mut [Object]) {
+    let (x, y) = objects[trap_id].pos();
+    let seen = tcod.is_visible(x, y);
+    if seen {
+        objects[trap_id].always_visible = true;
+    }
+    let victim = objects[victim_id].name.clone();
+    let mut message = |text: String, color: Color| {
+        if seen {
+            game.messages.add(text, color);
+        }
+    };
+
+    match objects[trap_id].trap {
+        Some(Trap::Spikes) => {
+            message(format!("Spikes shoot up into the {}'s feet!", victim), RED);
+            objects[victim_id].take_damage(SPIKE_TRAP_DAMAGE, game);
+        }
+        Some(Trap::Pit) => {
+            message(format!("The floor gives way and the {} falls into a pit!", victim), RED);
+            objects[victim_id].take_damage(PIT_TRAP_DAMAGE, game);
+        }
+        Some(Trap::Dart) => {
+            message(format!("A dart flies out of the wall and hits the {}!", victim), RED);
+            objects[victim_id].take_damage(DART_TRAP_DAMAGE, game);
+        }
+        Some(Trap::PoisonGas) => {
+            message("A cloud of poison gas bursts out of the floor!".to_string(), LIGHT_GREEN);
+            for object in objects.iter_mut() {
+                let in_cloud = object.distance(x, y) <= GAS_TRAP_RADIUS as f32;
+                if in_cloud && object.fighter.is_some() && object.alive {
+                    if seen {
+                        game.messages.add(format!("The {} chokes on the gas.", object.name), LIGHT_GREEN);
+                    }
+                    object.take_damage(GAS_TRAP_DAMAGE, game);
+                }
+            }
+        }
+        Some(Trap::Teleport) => {
+            // only somewhere the victim could have walked to, never a sealed pocket
+            let reached = reachable_tiles((x, y), &game.map);
+            let (width, height) = map_size(&game.map);
+            let destinations: Vec<(i32, i32)> = (0..width)
+                .flat_map(|x| (0..height).map(move |y| (x, y)))
+                .filter(|&(x, y)| reached[x as usize][y as usize] && !is_blocked(x, y, &game.map, objects))
+                .collect();
+            let text = match destinations.choose(&mut rand::thread_rng()) {
+                Some(&(new_x, new_y)) => {
+                    objects[victim_id].set_pos(new_x, new_y);
+                    format!("The {} vanishes in a flash of light!", victim)
+                }
+                None => format!("The {} flickers, but stays where it is.", victim),
+            };
+            if seen {
+                game.messages.add(text, LIGHT_MAGENTA);
+            }
+        }
+        Some(Trap::Alarm) => {
+            message("A loud bell starts ringing!".to_string(), LIGHT_YELLOW);
+            make_noise(x, y, ALARM_NOISE_RADIUS, &game.map, objects);
+        }
+        None => {}
+    }
+}
+
+/// Roll to notice hidden traps in sight near the player, with a chance of
+/// perception times the factor. Returns true if anything was found
+fn look_for_traps(radius: f32, factor: i32, tcod: &Tcod, game: &mut Game, objects: &mut [Object]) -> bool {
+    let perception = objects[PLAYER].fighter.map_or(0, |f| f.perception);
+    let (player_x, player_y) = objects[PLAYER].pos();
+    let mut found = false;
+    for object in objects.iter_mut() {
+        let nearby = object.distance(player_x, player_y) <= radius && tcod.is_visible(object.x, object.y);
+        if is_hidden_trap(object) && nearby && rand::thread_rng().gen_range(0, 100) < perception * factor {
+            object.always_visible = true;
+            game.messages.add(format!("You find a {}!", object.name), LIGHT_YELLOW);
+            found = true;
+        }
+    }
+    found
+}
+
+/// Try to disarm a found trap next to the player
+fn disarm_trap(tcod: &Tcod, game: &mut Game, objects: &mut Vec<Object>) -> PlayerAction {
+    let trap_id = objects.iter().position(|o| {
+        o.trap.is_some() && o.always_visible && objects[PLAYER].distance_to(o) < 1.5
+    });
+    let trap_id = match trap_id {
+        Some(trap_id) => trap_id,
+        None => {
+            game.messages.add("There is no trap next to you to disarm.", WHITE);
+            return PlayerAction::DidntTaketurn;
+        }
+    };
+
+    let perception = objects[PLAYER].fighter.map_or(0, |f| f.perception);
+    let chance = DISARM_BASE_CHANCE
+        + DISARM_LEVEL_BONUS * objects[PLAYER].level
+        + DISARM_PERCEPTION_BONUS * perception;
+    let roll = rand::thread_rng().gen_range(0, 100);
+    if roll < chance {
+        game.messages.add(format!("You disarm the {}.", objects[trap_id].name), LIGHT_GREEN);
+        objects.swap_remove(trap_id);
+        if let Some(fighter) = objects[PLAYER].fighter.as_mut() {
+            fighter.xp += DISARM_XP;
+        }
+    } else if roll >= chance + DISARM_FUMBLE_MARGIN {
+        game.messages.add(format!("You set off the {}!", objects[trap_id].name), RED);
+        spring_trap(trap_id, PLAYER, tcod, game, objects);
+    } else {
+        game.messages.add(format!("You fail to disarm the {}.", objects[trap_id].name), WHITE);
+    }
+    PlayerAction::TookTurn
+}
+
+/// Build the hand-made final level from its layout
+fn make_final_level(objects: &mut Vec<Object>) -> Map {
+    // the layout is the whole map, walls and all
+    let width = FINAL_LEVEL_LAYOUT[0].len();
+    let height = FINAL_LEVEL_LAYOUT.len();
+    let mut map = vec![vec![Tile::wall(); height]; width];
+
+    for (row, line) in FINAL_LEVEL_LAYOUT.iter().enumerate() {
+        for (column, glyph) in line.chars().enumerate() {
+            let (x, y) = (column as i32, row as i32);
+            if glyph == '#' {
+                continue;
+            }
+            map[x as usize][y as usize] = Tile::empty();
+            match glyph {
+                '@' => {
+                    objects[PLAYER].set_pos(x, y);
+                    objects.push(up_stairs(x, y));
+                }
+                'K' => objects.push(create_monster("ancient king", x, y)),
+                'C' => objects.push(create_artifact(x, y)),
+                'z' => objects.push(create_monster("skeleton", x, y)),
+                's' => objects.push(create_monster("scorpion man", x, y)),
+                _ => {}
+            }
+        }
+    }
+    map
+}
+
+fn up_stairs(x: i32, y: i32) -> Object {
+    let mut stairs = Object::new(x, y, '>', WHITE, "up stairs".to_string(), false);
+    stairs.always_visible = true;
+    stairs
+}
+
+/// The crown the player has come for
+fn create_artifact(x: i32, y: i32) -> Object {
+    let mut crown = Object::new(x, y, '*', GOLD, "Crown of the Ancient Kings".to_string(), false);
+    crown.item = Some(Item::Artifact);
+    crown.always_visible = true;
+    crown
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct Object {
+    x: i32,
+    y: i32,
+    glyph: char,
+    color: Color,
+    name: String,
+    blocks: bool,
+    alive: bool,
+    fighter: Option<Fighter>,
+    ai: Option<AI>,
+    item: Option<Item>,
+    always_visible: bool,
+    level: i32,
+    equipment: Option<Equipment>,
+    #[serde(default)]
+    group: Option<Group>,
+    faction: Faction,
+    #[serde(default)]
+    trap: Option<Trap>,
+    #[serde(default)]
+    light: Option<Light>,
+    // how many potions or scrolls are stacked in this one object
+    #[serde(default = "one")]
+    quantity: i32,
+}
+
+fn one() -> i32 {
+    1
+}
+
+/// Light given off by an object, fading with distance
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+struct Light {
+    radius: i32,
+    intensity: f32,
+    color: Color,
+}
+
+/// Something nasty waiting for whoever steps on it
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum Trap {
+    Spikes,
+    Pit,
+    Dart,
+    PoisonGas,
+    Teleport,
+    Alarm,
+}
+
+/// Membership of a pack of monsters that hunt together
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+struct Group {
+    id: u32,
+    leader: bool,
+}
+
+impl Object {
+    pub fn new(x: i32, y: i32, glyph: char, color: Color, name: String, blocks: bool) -> Self {
+        Object {
+            x: x,
+            y: y,
+            glyph: glyph,
+            color: color,
+            name: name,
+            blocks: blocks,
+            alive: false,
+            fighter: None,
+            ai: None,
+            item: None,
+            always_visible: false,
+            level: 1,
+            equipment: None,
+            group: None,
+            faction: Faction::Player,
+            trap: None,
+            light: None,
+            quantity: 1,
+        }
+    }
+
+    pub fn draw(&self, con: &mut dyn Console, camera: (i32, i32)) {
+        if let Some((x, y)) = map_to_screen(self.pos(), camera) {
+            con.set_default_foreground(self.color);
+            con.put_char(x, y, self.glyph, BackgroundFlag::None);
+        }
+    }
+
+    pub fn pos(&self) -> (i32, i32) {
+        (self.x, self.y)
+    }
+
+    pub fn set_pos(&mut self, x: i32, y: i32) {
+        self.x = x;
+        self.y = y;
+    }
+
+    /// return the distance to another object
+    pub fn distance_to(&self, other: &Object) -> f32 {
+        let dx = other.x - self.x;
+        let dy = other.y - self.y;
+        ((dx.pow(2) + dy.pow(2)) as f32).sqrt()
     }
 
     /// return distance to a position
@@ -374,12 +1619,23 @@ impl Object {
 
     // Combat
     pub fn take_damage(&mut self, damage: i32, game: &mut Game) -> Option<i32> {
-        // apply damage if possible
+        let max_hp = self.max_hp(game);
+
+        // apply damage if possible, which also rouses the victim
+        let mut badly_hurt = false;
         if let Some(fighter) = self.fighter.as_mut() {
             if damage > 0 {
+                let was_healthy = fighter.hp * 100 >= max_hp * FLEE_HP_PERCENT;
                 fighter.hp -= damage;
+                fighter.alertness = Alertness::Alert;
+                badly_hurt = was_healthy
+                    && fighter.hp > 0
+                    && fighter.hp * 100 < max_hp * FLEE_HP_PERCENT;
             }
         }
+        if badly_hurt {
+            self.check_morale(LOW_HP_FEAR, game);
+        }
 
         // check for death, call the death function
         if let Some(fighter) = self.fighter {
@@ -404,16 +1660,76 @@ impl Object {
         }
     }
 
+    /// roll against morale and run away on failure. Returns true if
+    /// the object lost its nerve
+    pub fn check_morale(&mut self, fear: i32, game: &mut Game) -> bool {
+        let morale = match self.fighter {
+            Some(fighter) => fighter.morale,
+            None => return false,
+        };
+        // only monsters that are thinking for themselves can panic
+        match self.ai {
+            None | Some(AI::Fleeing { .. }) => return false,
+            _ => {}
+        }
+
+        let chance = (100 - morale) * fear / 100;
+        if rand::thread_rng().gen_range(0, 100) >= chance {
+            return false;
+        }
+
+        let previous_ai = self.ai.take().unwrap();
+        self.ai = Some(AI::Fleeing {
+            previous_ai: Box::new(previous_ai),
+            num_turns: 0,
+        });
+        game.messages.add(format!("The {} turns to flee!", self.name), LIGHT_BLUE);
+        true
+    }
+
+    /// recover hit points over time, faster with a higher constitution
+    pub fn regenerate(&mut self, game: &Game) {
+        let max_hp = self.max_hp(game);
+
+        let amount = match self.fighter.as_mut() {
+            Some(fighter) if fighter.hp < max_hp => {
+                fighter.regen += fighter.constitution;
+                let amount = fighter.regen / REGEN_THRESHOLD;
+                fighter.regen %= REGEN_THRESHOLD;
+                amount
+            }
+            _ => 0,
+        };
+
+        if amount > 0 {
+            self.heal(amount, game);
+        }
+    }
+
     pub fn attack(&mut self, target: &mut Object, game: &mut Game) {
+        self.provoke(target, game);
         // a simple formula for attack damage
-        let damage = self.power(game) - target.defense(game);
+        let mut damage = self.power(game) - target.defense(game);
         if damage > 0 {
-            // make the target take damage
-            game.messages.add(
-                format!(
-                "{} attacks {} for {} hit points.",
-                self.name, target.name, damage
-            ), ORANGE);
+            if target.fighter.map_or(false, |f| f.alertness == Alertness::Asleep) {
+                // sleeping targets can't defend themselves
+                damage *= SNEAK_ATTACK_MULTIPLIER;
+                game.messages.add(
+                    format!(
+                    "{} sneak attacks the sleeping {} for {} hit points!",
+                    self.name, target.name, damage
+                ), LIGHT_ORANGE);
+            } else {
+                // make the target take damage
+                game.messages.add(
+                    format!(
+                    "{} attacks {} for {} hit points.",
+                    self.name, target.name, damage
+                ), ORANGE);
+            }
+            if self.has_affix(Affix::Vampiric, game) {
+                self.heal((damage * VAMPIRIC_PERCENT / 100).max(1), game);
+            }
             if let Some(xp) = target.take_damage(damage, game) {
                 // yield experience to player if target killed
                 self.fighter.as_mut().unwrap().xp += xp;
@@ -426,9 +1742,20 @@ impl Object {
         }
     }
 
+    /// Turn the target's faction against ours if it was neutral
+    pub fn provoke(&self, target: &Object, game: &mut Game) {
+        if game.factions.provoke(target.faction, self.faction) && self.faction == Faction::Player {
+            game.messages.add(
+                format!("The {} turns hostile!", target.name),
+                LIGHT_RED,
+            );
+        }
+    }
+
     pub fn ranged_attack(&mut self, target: &mut Object, game: &mut Game, range: f32) {
 
         let (x, y) = target.pos();
+        self.provoke(target, game);
 
         // confirm target is in range
         if self.distance(x, y) <= range {
@@ -459,6 +1786,17 @@ impl Object {
         }
     }
 
+    /// The name the player knows the object by, with its enchantment in
+    /// front once they have found it out
+    pub fn known_name(&self) -> String {
+        match self.equipment {
+            Some(equipment) if equipment.enchantment != 0 && equipment.is_identified() => {
+                format!("{:+} {}", equipment.enchantment, self.name)
+            }
+            _ => self.name.clone(),
+        }
+    }
+
     /// Equip object and show a message about it
     pub fn equip(&mut self, messages: &mut Messages) {
         if self.item.is_none() {
@@ -471,10 +1809,20 @@ impl Object {
         if let Some(ref mut equipment) = self.equipment {
             if !equipment.equipped {
                 equipment.equipped = true;
+                // wearing an item shows what magic is on it
+                equipment.identified = true;
+                let (slot, cursed) = (equipment.slot, equipment.cursed);
+                let name = self.known_name();
                 messages.add(
-                    format!("Equipped {} on {:?}.", self.name, equipment.slot),
+                    format!("Equipped {} on {:?}.", name, slot),
                     LIGHT_GREEN,
                 );
+                if cursed {
+                    messages.add(
+                        format!("The {} is cursed! It clings to you.", name),
+                        RED,
+                    );
+                }
             }
         } else {
             messages.add(
@@ -484,28 +1832,39 @@ impl Object {
         }
     }
 
-    /// Dequip object and show a message about it
-    pub fn dequip(&mut self, messages: &mut Messages) {
+    /// Dequip object and show a message about it. Cursed items won't come
+    /// off, so this returns false for them
+    pub fn dequip(&mut self, messages: &mut Messages) -> bool {
         if self.item.is_none() {
             messages.add(
                 format!("Can't dequip {:?} because it's not an item.", self),
                 RED,
             );
-            return;
+            return false;
         };
+        let name = self.known_name();
         if let Some(ref mut equipment) = self.equipment {
+            if equipment.equipped && equipment.cursed {
+                messages.add(
+                    format!("The {} is cursed! You can't take it off.", name),
+                    RED,
+                );
+                return false;
+            }
             if equipment.equipped {
                 equipment.equipped = false;
                 messages.add(
-                    format!("Dequipped {} from {:?}.", self.name, equipment.slot),
+                    format!("Dequipped {} from {:?}.", name, equipment.slot),
                     LIGHT_YELLOW,
                 );
             }
+            true
         } else {
             messages.add(
                 format!("Can't dequip {:?} because it's not an Equipment.", self),
                 RED,
             );
+            false
         }
     }
 
@@ -546,6 +1905,23 @@ impl Object {
         base_max_hp + bonus
     }
 
+    /// how hard it is for monsters to notice this object: heavy equipment
+    /// and the glow of a torch give the player away
+    pub fn stealth(&self, game: &Game) -> i32 {
+        let weight: i32 = self
+            .get_all_equipped(game)
+            .iter()
+            .map(|e| e.weight)
+            .sum();
+        let torch = if self.name == "player" && lit_torch(game).is_some() {
+            TORCH_STEALTH_PENALTY
+        } else {
+            0
+        };
+
+        STEALTH_BASE - weight - torch
+    }
+
     pub fn get_all_equipped(&self, game: &Game) -> Vec<Equipment> {
         if self.name == "player" {
             game.inventory
@@ -558,6 +1934,30 @@ impl Object {
         }
     }
 
+    /// Potions and scrolls of the same kind pile up into one stack
+    pub fn stacks_with(&self, other: &Object) -> bool {
+        let stackable = |object: &Object| {
+            object.item.is_some() && object.equipment.is_none() && object.item != Some(Item::Artifact)
+        };
+        stackable(self) && stackable(other) && self.item == other.item && self.name == other.name
+    }
+
+    /// True if the object has equipment with this affix on
+    pub fn has_affix(&self, affix: Affix, game: &Game) -> bool {
+        self.get_all_equipped(game)
+            .iter()
+            .any(|e| e.affix == Some(affix))
+    }
+
+    /// How much of some fire damage actually gets through
+    pub fn fire_damage(&self, damage: i32, game: &Game) -> i32 {
+        if self.has_affix(Affix::FireResistance, game) {
+            damage / 2
+        } else {
+            damage
+        }
+    }
+
 }
 
 fn level_up(tcod: &mut Tcod, game: &mut Game, objects: &mut [Object]) {
@@ -582,7 +1982,10 @@ fn level_up(tcod: &mut Tcod, game: &mut Game, objects: &mut [Object]) {
             choice = menu(
                 "Level up! Chose a stat to raise:\n",
                 &[
-                    format!("Constitution (+20 HP, from {})", fighter.base_max_hp),
+                    format!(
+                        "Constitution (+20 HP, faster healing, from {})",
+                        fighter.base_max_hp
+                    ),
                     format!("Strength (+1 attack, from {})", fighter.base_power),
                     format!("Agility (+1 defense, from {})", fighter.base_defense),
                 ],
@@ -595,6 +1998,7 @@ fn level_up(tcod: &mut Tcod, game: &mut Game, objects: &mut [Object]) {
             0 => {
                 fighter.base_max_hp += 20;
                 fighter.hp += 20;
+                fighter.constitution += 2;
             }
             1 => {
                 fighter.base_power += 1;
@@ -621,6 +2025,7 @@ fn monster_death(monster: &mut Object, game: &mut Game) {
     game.messages.add(
         format!("{} is dead! You gain {} xp!", monster.name, monster.fighter.unwrap().xp),
         ORANGE);
+    game.recent_deaths.push(monster.pos());
     monster.glyph = '%';
     monster.color = DARK_RED;
     monster.blocks = false;
@@ -637,9 +2042,115 @@ struct Fighter {
     base_defense: i32,
     base_power: i32,
     xp: i32,
+    #[serde(default = "default_constitution")]
+    constitution: i32,
+    #[serde(default)]
+    regen: i32,
+    #[serde(default = "default_perception")]
+    perception: i32,
+    #[serde(default)]
+    alertness: Alertness,
+    #[serde(default = "default_morale")]
+    morale: i32,
+    // turns left on fire, and turns still spent wading through slow terrain
+    #[serde(default)]
+    burning: i32,
+    #[serde(default)]
+    delay: i32,
+    // damage done to a wall in the way, for creatures strong enough to
+    // smash through
+    #[serde(default)]
+    smash: i32,
+    // turns the player has left stumbling around confused; monsters use
+    // their AI for this instead
+    #[serde(default)]
+    confused: i32,
     on_death: DeathCallback,
 }
 
+// fighters in older saves get average constitution
+fn default_constitution() -> i32 {
+    10
+}
+
+// see as far as an ordinary monster
+fn default_perception() -> i32 {
+    6
+}
+
+// and fight to the death, as they always did
+fn default_morale() -> i32 {
+    100
+}
+
+/// How aware a monster is of the player
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+enum Alertness {
+    Asleep,
+    #[default]
+    Awake,
+    Alert,
+}
+
+/// Sides a creature can belong to
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum Faction {
+    Player,
+    Chaos,
+    Trolls,
+    Sellswords,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum Relation {
+    Friendly,
+    Neutral,
+    Hostile,
+}
+
+/// How every faction feels about every other, indexed by faction
+#[derive(Serialize, Deserialize)]
+struct FactionTable {
+    relations: [[Relation; 4]; 4],
+}
+
+impl Default for FactionTable {
+    fn default() -> Self {
+        use Relation::*;
+        // Chaos hates everything, Trolls keep to themselves until provoked
+        // and Sellswords wait for someone to hire them
+        FactionTable {
+            relations: [
+                // Player    Chaos    Trolls   Sellswords
+                [Friendly, Hostile, Neutral, Neutral], // Player
+                [Hostile, Friendly, Hostile, Hostile], // Chaos
+                [Neutral, Hostile, Friendly, Neutral], // Trolls
+                [Neutral, Hostile, Neutral, Friendly], // Sellswords
+            ],
+        }
+    }
+}
+
+impl FactionTable {
+    pub fn relation(&self, from: Faction, to: Faction) -> Relation {
+        self.relations[from as usize][to as usize]
+    }
+
+    pub fn hostile(&self, from: Faction, to: Faction) -> bool {
+        self.relation(from, to) == Relation::Hostile
+    }
+
+    /// Neutral factions turn hostile on whoever attacks them, and stay that way
+    pub fn provoke(&mut self, victim: Faction, attacker: Faction) -> bool {
+        if self.relation(victim, attacker) != Relation::Neutral {
+            return false;
+        }
+        self.relations[victim as usize][attacker as usize] = Relation::Hostile;
+        self.relations[attacker as usize][victim as usize] = Relation::Hostile;
+        true
+    }
+}
+
 #[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
 enum DeathCallback {
     Player,
@@ -668,6 +2179,13 @@ enum Item {
     Shield,
     Helmet,
     Bow,
+    Summon,
+    Charm,
+    Artifact,
+    Pick,
+    Torch,
+    Identify,
+    RemoveCurse,
 }
 
 enum UseResult {
@@ -688,13 +2206,102 @@ struct Equipment {
     range: i32,
     damage: i32,
     charges: i32,
+    #[serde(default)]
+    weight: i32,
+    // +N/-N, already added to the item's main bonus
+    #[serde(default)]
+    enchantment: i32,
+    #[serde(default)]
+    affix: Option<Affix>,
+    // a cursed item can't be taken off until the curse is lifted
+    #[serde(default)]
+    cursed: bool,
+    // the enchantment stays a mystery until the item is worn or identified
+    #[serde(default)]
+    identified: bool,
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
-enum Slot {
-    LeftHand,
-    RightHand,
-    Head,
+impl Equipment {
+    /// How special the item is, by how much magic it has on it. Curses and
+    /// other weakening enchantments are nothing special
+    pub fn rarity(&self) -> Rarity {
+        match (self.enchantment > 0, self.affix.is_some()) {
+            (true, true) => Rarity::Rare,
+            (false, false) => Rarity::Common,
+            _ if self.enchantment >= RARE_ENCHANTMENT => Rarity::Rare,
+            _ => Rarity::Magic,
+        }
+    }
+
+    /// Add to whatever bonus the item is good for
+    pub fn boost(&mut self, amount: i32) {
+        match self.slot {
+            Slot::RightHand => self.power_bonus += amount,
+            Slot::LeftHand => self.defense_bonus += amount,
+            Slot::Head => self.max_hp_bonus += ENCHANT_HP * amount,
+            Slot::Back => self.damage += amount,
+        }
+    }
+
+    pub fn is_identified(&self) -> bool {
+        self.identified || self.equipped
+    }
+
+    /// The item as far as the player can tell, leaving out an enchantment
+    /// they haven't found out about
+    pub fn as_known(&self) -> Equipment {
+        let mut known = *self;
+        if !self.is_identified() {
+            known.boost(-self.enchantment);
+            known.enchantment = 0;
+        }
+        known
+    }
+}
+
+/// A magical property some equipment is found with
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum Affix {
+    // wade through water and clamber over rubble without slowing down
+    Speed,
+    // take half damage from fire and lava
+    FireResistance,
+    // melee hits heal the wielder
+    Vampiric,
+}
+
+impl std::fmt::Display for Affix {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            Affix::Speed => write!(f, "of Speed"),
+            Affix::FireResistance => write!(f, "of Fire Resistance"),
+            Affix::Vampiric => write!(f, "of Vampirism"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Rarity {
+    Common,
+    Magic,
+    Rare,
+}
+
+impl Rarity {
+    pub fn color(self) -> Color {
+        match self {
+            Rarity::Common => WHITE,
+            Rarity::Magic => LIGHT_BLUE,
+            Rarity::Rare => YELLOW,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum Slot {
+    LeftHand,
+    RightHand,
+    Head,
     Back,
 }
 
@@ -724,18 +2331,31 @@ fn get_equipped_in_slot(slot: Slot, inventory: &[Object]) -> Option<usize> {
 
 /// Add to player inventory and remove from map
 fn pick_item_up(object_id: usize, game: &mut Game, objects: &mut Vec<Object>) {
+    // potions and scrolls join a stack of the same kind, which needs no new slot
+    let stack = game
+        .inventory
+        .iter()
+        .position(|item| item.stacks_with(&objects[object_id]));
+    if let Some(stack) = stack {
+        let item = objects.swap_remove(object_id);
+        game.messages.add(
+            format!("You picked up {}!", game.identification.describe(&item)), GREEN);
+        game.inventory[stack].quantity += item.quantity;
+        return;
+    }
+
     if game.inventory.len() >= 26 {
         game.messages.add(
             format!(
                 "Your inventory is full. Cannot pick up {}",
-                objects[object_id].name
+                game.identification.name_of(&objects[object_id])
             ),
             RED,
         );
     } else {
         let item = objects.swap_remove(object_id);
         game.messages.add(
-            format!("You picked up a {}!", item.name), GREEN);
+            format!("You picked up {}!", game.identification.describe(&item)), GREEN);
         let index = game.inventory.len();
         let slot = item.equipment.map(|e| e.slot);
         game.inventory.push(item);
@@ -750,25 +2370,48 @@ fn pick_item_up(object_id: usize, game: &mut Game, objects: &mut Vec<Object>) {
 }
 
 // Use items
-fn use_item(inventory_id: usize, tcod: &mut Tcod, game: &mut Game, objects: &mut [Object]) {
+fn use_item(inventory_id: usize, tcod: &mut Tcod, game: &mut Game, objects: &mut Vec<Object>) {
     use Item::*;
 
     // just call use_function if it is defined
     if let Some(item) = game.inventory[inventory_id].item {
-        let on_use = match item {
-            Heal => cast_heal,
-            Lightning => cast_lightning,
-            Confuse => cast_confuse,
-            Fireball => cast_fireball,
-            Sword => toggle_equipment,
-            Shield => toggle_equipment,
-            Helmet => toggle_equipment,
-            Bow => player_ranged_attack,
+        let appearance = game.identification.name_of(&game.inventory[inventory_id]);
+        let name = game.inventory[inventory_id].name.clone();
+        let result = if item == Summon {
+            // summoning adds a new object, so it needs the whole list
+            cast_summon(inventory_id, tcod, game, objects)
+        } else {
+            let on_use = match item {
+                Heal => cast_heal,
+                Lightning => cast_lightning,
+                Confuse => cast_confuse,
+                Fireball => cast_fireball,
+                Sword => toggle_equipment,
+                Shield => toggle_equipment,
+                Helmet => toggle_equipment,
+                Pick => toggle_equipment,
+                Torch => toggle_equipment,
+                Identify => cast_identify,
+                RemoveCurse => cast_remove_curse,
+                Bow => player_ranged_attack,
+                Charm => cast_charm,
+                Artifact => admire_artifact,
+                Summon => unreachable!(),
+            };
+            on_use(inventory_id, tcod, game, objects)
         };
-        match on_use(inventory_id, tcod, game, objects) {
+        // using an item shows what it was
+        let used = !matches!(result, UseResult::Cancelled);
+        if used && game.identification.identify(item) {
+            game.messages.add(format!("The {} was a {}.", appearance, name), LIGHT_CYAN);
+        }
+        match result {
             UseResult::UsedUp => {
-                // destroy after use, unless cancelled
-                game.inventory.remove(inventory_id);
+                // destroy after use, unless cancelled, one from a stack at a time
+                game.inventory[inventory_id].quantity -= 1;
+                if game.inventory[inventory_id].quantity <= 0 {
+                    game.inventory.remove(inventory_id);
+                }
             }
             UseResult::UsedAndKept => {} // do nothing
             UseResult::UseCharge => {
@@ -800,35 +2443,100 @@ fn toggle_equipment(
         None => return UseResult::Cancelled,
     };
 
-    // if the slot is already being used, dequip whatever is ther first
-    if let Some(current) = get_equipped_in_slot(equipment.slot, &game.inventory) {
-        game.inventory[current].dequip(&mut game.messages);
+    if equipment.equipped {
+        if !game.inventory[inventory_id].dequip(&mut game.messages) {
+            return UseResult::Cancelled;
+        }
+        return UseResult::UsedAndKept;
     }
 
-    if equipment.equipped {
-        game.inventory[inventory_id].dequip(&mut game.messages);
-    } else {
-        game.inventory[inventory_id].equip(&mut game.messages);
+    // if the slot is already being used, dequip whatever is ther first
+    if let Some(current) = get_equipped_in_slot(equipment.slot, &game.inventory) {
+        if !game.inventory[current].dequip(&mut game.messages) {
+            return UseResult::Cancelled;
+        }
     }
+    game.inventory[inventory_id].equip(&mut game.messages);
     UseResult::UsedAndKept
 }
 
-fn drop_item(inventory_id: usize, game: &mut Game, objects: &mut Vec<Object>) {
-    let mut item = game.inventory.remove(inventory_id);
-
-    if item.equipment.is_some() {
-        item.dequip(&mut game.messages);
+fn drop_item(inventory_id: usize, tcod: &mut Tcod, game: &mut Game, objects: &mut Vec<Object>) {
+    // cursed equipment won't come off to be dropped
+    if game.inventory[inventory_id].equipment.is_some()
+        && !game.inventory[inventory_id].dequip(&mut game.messages)
+    {
+        return;
     }
 
+    // from a stack, drop as many as the player asks for
+    let quantity = game.inventory[inventory_id].quantity;
+    let amount = if quantity > 1 {
+        let name = game.identification.name_of(&game.inventory[inventory_id]);
+        let header = format!("Drop how many {}? (1-{}, Enter for all)", plural(&name), quantity);
+        match ask_quantity(&header, quantity, &mut tcod.root) {
+            Some(amount) => amount,
+            None => return,
+        }
+    } else {
+        1
+    };
+    let mut item = if amount < quantity {
+        game.inventory[inventory_id].quantity -= amount;
+        let mut split = game.inventory[inventory_id].clone();
+        split.quantity = amount;
+        split
+    } else {
+        game.inventory.remove(inventory_id)
+    };
+
     item.set_pos(objects[PLAYER].x, objects[PLAYER].y);
     game.messages.add(
         format!(
-            "You dropped a {}", item.name,
+            "You dropped {}", game.identification.describe(&item),
         ), YELLOW
     );
     objects.push(item);
 }
 
+/// Ask the player to type a number from 1 up to `max`. Enter on its own
+/// means all of them, Escape cancels
+fn ask_quantity(header: &str, max: i32, root: &mut Root) -> Option<i32> {
+    let mut typed = String::new();
+    loop {
+        let text = format!("{}\n\n{}_", header, typed);
+        let height = root.get_height_rect(0, 0, QUANTITY_WIDTH, SCREEN_HEIGHT, &text);
+        let mut window = Offscreen::new(QUANTITY_WIDTH, height);
+        window.set_default_foreground(WHITE);
+        window.print_rect_ex(0, 0, QUANTITY_WIDTH, height, BackgroundFlag::None, TextAlignment::Left, &text);
+        let x = SCREEN_WIDTH / 2 - QUANTITY_WIDTH / 2;
+        let y = SCREEN_HEIGHT / 2 - height / 2;
+        blit(&window, (0, 0), (QUANTITY_WIDTH, height), root, (x, y), 1.0, 0.7);
+        root.flush();
+
+        let key = root.wait_for_keypress(true);
+        match key.code {
+            Enter | NumPadEnter if typed.is_empty() => return Some(max),
+            Enter | NumPadEnter => {
+                return typed.parse().ok().filter(|&n| n >= 1).map(|n: i32| n.min(max));
+            }
+            Escape => return None,
+            Backspace => {
+                typed.pop();
+            }
+            _ if key.printable.is_ascii_digit() && typed.len() < 4 => typed.push(key.printable),
+            _ => {}
+        }
+    }
+}
+
+/// The plural of an item name: "healing potions", "scrolls of fireball"
+fn plural(name: &str) -> String {
+    match name.strip_prefix("scroll ") {
+        Some(rest) => format!("scrolls {}", rest),
+        None => format!("{}s", name),
+    }
+}
+
 fn cast_heal(
     _inventory_id: usize,
     _tcod: &mut Tcod,
@@ -857,14 +2565,10 @@ fn cast_lightning(
     objects: &mut [Object],
 ) -> UseResult {
     // find closest enemy in max range and dmage it
-    let monster_id = closest_monster(tcod, objects, LIGHTNING_RANGE);
+    let monster_id = closest_monster(tcod, game, objects, LIGHTNING_RANGE);
     if let Some(monster_id) = monster_id {
         // zap it!
-        game.messages.add(
-            format!("A lightning bolt strikes the {} with a loud thunderclap! \
-            It takes {} damage!", objects[monster_id].name, LIGHTNING_DAMAGE), LIGHT_BLUE,
-        );
-        objects[monster_id].take_damage(LIGHTNING_DAMAGE, game);
+        lightning_bolt(monster_id, LIGHTNING_DAMAGE, game, objects);
         UseResult::UsedUp
     } else {
         // no enemy found in max range
@@ -888,25 +2592,203 @@ fn cast_confuse(
 
     let monster_id = target_monster(tcod, game, objects, Some(CONFUSE_RANGE as f32));
     if let Some(monster_id) = monster_id {
-        let old_ai = objects[monster_id].ai.take().unwrap_or(AI::Basic);
+        objects[PLAYER].provoke(&objects[monster_id], game);
+        confuse(monster_id, game, objects);
+        UseResult::UsedUp
+    } else {
+        // no enemy found within max range
+        game.messages.add("No enemy is close enough to strike", RED);
+        UseResult::Cancelled
+    }
+}
+
+fn admire_artifact(
+    _inventory_id: usize,
+    _tcod: &mut Tcod,
+    game: &mut Game,
+    _objects: &mut [Object],
+) -> UseResult {
+    game.messages.add(
+        "The crown hums with the power of the Ancient Kings. Carry it back to the surface!",
+        GOLD,
+    );
+    UseResult::UsedAndKept
+}
+
+fn cast_identify(
+    inventory_id: usize,
+    tcod: &mut Tcod,
+    game: &mut Game,
+    _objects: &mut [Object],
+) -> UseResult {
+    // only what the player doesn't already know is worth choosing from
+    let unknown: Vec<usize> = (0..game.inventory.len())
+        .filter(|&id| id != inventory_id)
+        .filter(|&id| {
+            let item = &game.inventory[id];
+            item.item.map_or(false, |kind| !game.identification.is_known(kind))
+                || item.equipment.map_or(false, |equipment| !equipment.is_identified())
+        })
+        .collect();
+    if unknown.is_empty() {
+        game.messages.add("You have nothing to identify.", WHITE);
+        return UseResult::UsedUp;
+    }
+
+    let choices: Vec<Object> = unknown.iter().map(|&id| game.inventory[id].clone()).collect();
+    let choice = inventory_menu(
+        &choices,
+        &game.identification,
+        "Choose an item to identify, or any other key to cancel.\n",
+        &mut tcod.root,
+    );
+    let item_id = match choice {
+        Some(choice) => unknown[choice],
+        None => return UseResult::Cancelled,
+    };
+    let old_name = game.identification.name_of(&game.inventory[item_id]);
+    if let Some(kind) = game.inventory[item_id].item {
+        game.identification.identify(kind);
+    }
+    if let Some(equipment) = game.inventory[item_id].equipment.as_mut() {
+        equipment.identified = true;
+    }
+    let new_name = game.identification.name_of(&game.inventory[item_id]);
+    let message = if new_name == old_name {
+        format!("The {} carries no enchantment.", old_name)
+    } else {
+        format!("The {} is a {}.", old_name, new_name)
+    };
+    game.messages.add(message, LIGHT_CYAN);
+    UseResult::UsedUp
+}
+
+fn cast_remove_curse(
+    _inventory_id: usize,
+    _tcod: &mut Tcod,
+    game: &mut Game,
+    _objects: &mut [Object],
+) -> UseResult {
+    let mut lifted = 0;
+    for equipment in game.inventory.iter_mut().filter_map(|item| item.equipment.as_mut()) {
+        if equipment.cursed {
+            equipment.cursed = false;
+            lifted += 1;
+        }
+    }
+    if lifted > 0 {
+        game.messages.add("You feel as if someone is watching over you.", LIGHT_CYAN);
+    } else {
+        game.messages.add("You feel a moment of calm, but nothing else happens.", WHITE);
+    }
+    UseResult::UsedUp
+}
+
+fn cast_charm(
+    _inventory_id: usize,
+    tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut [Object],
+) -> UseResult {
+    // ask the player for a monster to win over
+    game.messages.add(
+        "Left-click a monster to charm it, or right-click to cancel.",
+        LIGHT_CYAN,
+    );
+    let monster_id = match target_monster(tcod, game, objects, Some(CHARM_RANGE as f32)) {
+        Some(monster_id) => monster_id,
+        None => return UseResult::Cancelled,
+    };
+    if is_ally(&objects[monster_id]) {
+        game.messages.add(
+            format!("The {} is already on your side.", objects[monster_id].name),
+            WHITE,
+        );
+        return UseResult::Cancelled;
+    }
+    if is_boss(&objects[monster_id]) {
+        game.messages.add(
+            format!("The {} laughs off your feeble charm!", objects[monster_id].name),
+            RED,
+        );
+        return UseResult::UsedUp;
+    }
+    make_ally(&mut objects[monster_id], Order::Follow);
+    game.messages.add(
+        format!("The {} gazes at you adoringly and joins your side!", objects[monster_id].name),
+        LIGHT_GREEN,
+    );
+    UseResult::UsedUp
+}
+
+fn cast_summon(
+    _inventory_id: usize,
+    _tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut Vec<Object>,
+) -> UseResult {
+    // call a hound to the player's side
+    let (x, y) = objects[PLAYER].pos();
+    match free_spot_near(x, y, &game.map, objects) {
+        Some((x, y)) => {
+            objects.push(create_monster("spirit hound", x, y));
+            game.messages.add("A spirit hound bounds out of thin air to your side!", LIGHT_GREEN);
+            UseResult::UsedUp
+        }
+        None => {
+            game.messages.add("There is no room for anything to appear.", RED);
+            UseResult::Cancelled
+        }
+    }
+}
+
+/// Turn a creature into one of the player's allies
+fn make_ally(object: &mut Object, order: Order) {
+    object.faction = Faction::Player;
+    object.group = None;
+    object.ai = Some(AI::Ally { order });
+    if let Some(fighter) = object.fighter.as_mut() {
+        fighter.alertness = Alertness::Alert;
+        fighter.morale = 100;
+    }
+}
+
+/// Strike a target with a bolt of lightning, returning the experience
+/// for killing it
+fn lightning_bolt(target_id: usize, damage: i32, game: &mut Game, objects: &mut [Object]) -> Option<i32> {
+    game.messages.add(
+        format!("A lightning bolt strikes the {} with a loud thunderclap! \
+        It takes {} damage!", objects[target_id].name, damage), LIGHT_BLUE,
+    );
+    let xp = objects[target_id].take_damage(damage, game);
+    let (x, y) = objects[target_id].pos();
+    make_noise(x, y, THUNDER_NOISE_RADIUS, &game.map, objects);
+    xp
+}
+
+/// Confuse a target for a while. Monsters get their old AI back afterwards,
+/// while a confused player stumbles around until it wears off
+fn confuse(target_id: usize, game: &mut Game, objects: &mut [Object]) {
+    if target_id == PLAYER {
+        if let Some(fighter) = objects[PLAYER].fighter.as_mut() {
+            fighter.confused = CONFUSE_NUM_TURNS;
+        }
+        game.messages.add("Your head spins and you start to stumble around!", LIGHT_RED);
+    } else {
+        let old_ai = objects[target_id].ai.take().unwrap_or(AI::Basic);
         // replace the monster's AI with a confused one
         // after some turns it will restore to the old AI
-        objects[monster_id].ai = Some(AI::Confused {
+        objects[target_id].ai = Some(AI::Confused {
             previous_ai: Box::new(old_ai),
             num_turns: CONFUSE_NUM_TURNS,
         });
         game.messages.add(
             format!(
                 "The eyes of {} look vacant as it starts to stumble around!",
-                objects[monster_id].name
-        ),
-        LIGHT_GREEN,
-    );
-    UseResult::UsedUp
-    } else {
-        // no enemy found within max range
-        game.messages.add("No enemy is close enough to strike", RED);
-        UseResult::Cancelled
+                objects[target_id].name
+            ),
+            LIGHT_GREEN,
+        );
     }
 }
 
@@ -937,22 +2819,46 @@ fn cast_fireball(
 
     for (id, object) in objects.iter_mut().enumerate() {
         if object.distance(x, y) <= FIREBALL_RADIUS as f32 && object.fighter.is_some() {
+            if id != PLAYER {
+                game.factions.provoke(object.faction, Faction::Player);
+            }
+            let damage = object.fire_damage(FIREBALL_DAMAGE, game);
             game.messages.add(
                 format!(
                     "The {} gets burned for {} hit points.",
-                    object.name, FIREBALL_DAMAGE
+                    object.name, damage
                 ),
                 ORANGE,
             );
-            if let Some(xp) = object.take_damage(FIREBALL_DAMAGE, game) {
+            if let Some(xp) = object.take_damage(damage, game) {
                 if id != PLAYER {
                     // don't reward player for burning themselves
                     xp_to_gain += xp;
                 }
             };
+            // survivors are left on fire
+            if let Some(fighter) = object.fighter.as_mut() {
+                fighter.burning = BURN_TURNS;
+            }
         }
     }
     objects[PLAYER].fighter.as_mut().unwrap().xp += xp_to_gain;
+
+    // walls the blast reaches are weakened, and cave in if already worn
+    for wall_x in (x - FIREBALL_RADIUS)..=(x + FIREBALL_RADIUS) {
+        for wall_y in (y - FIREBALL_RADIUS)..=(y + FIREBALL_RADIUS) {
+            let in_blast = (wall_x - x).pow(2) + (wall_y - y).pow(2) <= FIREBALL_RADIUS.pow(2)
+                && in_map(wall_x, wall_y, &game.map)
+                && line_of_sight((x, y), (wall_x, wall_y), &game.map);
+            if in_blast && damage_wall(wall_x, wall_y, FIREBALL_WALL_DAMAGE, &mut game.map) {
+                break_wall(wall_x, wall_y, Tile::rubble(), &mut game.map, tcod);
+                if tcod.is_visible(wall_x, wall_y) {
+                    game.messages.add("A wall collapses!", ORANGE);
+                }
+            }
+        }
+    }
+    make_noise(x, y, FIREBALL_NOISE_RADIUS, &game.map, objects);
     UseResult::UsedUp
 }
 
@@ -1015,15 +2921,22 @@ fn player_ranged_attack(
 }
 
 /// Find closest enemy, up to a max range and in FOV
-fn closest_monster(tcod: &mut Tcod, objects: &mut [Object], max_range: i32) -> Option<usize> {
+fn closest_monster(tcod: &mut Tcod, game: &Game, objects: &mut [Object], max_range: i32) -> Option<usize> {
+    closest_visible_monster(tcod, game, objects)
+        .filter(|&id| objects[PLAYER].distance_to(&objects[id]) < (max_range + 1) as f32)
+}
+
+/// Find closest enemy in FOV, however far away
+fn closest_visible_monster(tcod: &Tcod, game: &Game, objects: &[Object]) -> Option<usize> {
     let mut closest_enemy = None;
-    let mut closest_dist = (max_range + 1) as f32;
+    let mut closest_dist = f32::INFINITY;
 
     for (id, object) in objects.iter().enumerate() {
         if (id != PLAYER)
             && object.fighter.is_some()
             && object.ai.is_some()
-            && tcod.fov.is_in_fov(object.x, object.y)
+            && game.factions.hostile(object.faction, Faction::Player)
+            && tcod.is_visible(object.x, object.y)
         {
             // calculate distance between this object and the player
             let dist = objects[PLAYER].distance_to(object);
@@ -1037,87 +2950,1041 @@ fn closest_monster(tcod: &mut Tcod, objects: &mut [Object], max_range: i32) -> O
     closest_enemy
 }
 
+// archers in older saves start with a full quiver
+fn default_ammo() -> i32 {
+    8
+}
+
 // basic AI functionality
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 enum AI {
     Basic,
     Ranged {
         range: f32,
+        #[serde(default = "default_ammo")]
+        ammo: i32,
+        #[serde(default)]
+        cooldown: i32,
     },
     Confused {
         previous_ai: Box<AI>,
         num_turns: i32,
     },
+    // heading for the last place the player was seen or heard
+    Investigating {
+        previous_ai: Box<AI>,
+        x: i32,
+        y: i32,
+    },
+    // looking around the place the player was lost
+    Searching {
+        previous_ai: Box<AI>,
+        x: i32,
+        y: i32,
+        num_turns: i32,
+    },
+    // running away, counting the turns since it was last chased
+    Fleeing {
+        previous_ai: Box<AI>,
+        num_turns: i32,
+    },
+    Caster {
+        spells: Vec<Spell>,
+        mana: i32,
+        cooldown: i32,
+    },
+    // casts spells when it can, and fights hand to hand otherwise
+    Boss {
+        spells: Vec<Spell>,
+        mana: i32,
+        cooldown: i32,
+    },
+    // fights on the player's side, doing as it is told
+    Ally {
+        order: Order,
+    },
+}
+
+/// Commands the player can give to allies
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+enum Order {
+    Follow,
+    Stay,
+    // go after whatever stands at this spot, tracking it as it moves
+    Attack { x: i32, y: i32 },
+}
+
+/// Spells monsters can cast
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+enum Spell {
+    HealAlly,
+    Confuse,
+    Summon,
+    Bolt,
+    RaiseDead,
+}
+
+impl Spell {
+    fn mana_cost(self) -> i32 {
+        match self {
+            Spell::HealAlly => 6,
+            Spell::Confuse => 8,
+            Spell::Summon => 15,
+            Spell::Bolt => 5,
+            Spell::RaiseDead => 12,
+        }
+    }
 }
 
-fn ai_take_turn(monster_id: usize, tcod: &mut Tcod, game: &mut Game, objects: &mut [Object]) {
+fn ai_take_turn(monster_id: usize, tcod: &mut Tcod, game: &mut Game, objects: &mut Vec<Object>) {
     use AI::*;
 
-    if let Some(ai) = objects[monster_id].ai.take() {
+    let start = objects[monster_id].pos();
+
+    // wading through slow terrain uses up the turn
+    let delayed = match objects[monster_id].fighter.as_mut() {
+        Some(fighter) if fighter.delay > 0 => {
+            fighter.delay -= 1;
+            true
+        }
+        _ => false,
+    };
+
+    let ai = if delayed { None } else { objects[monster_id].ai.take() };
+    if let Some(ai) = ai {
         let new_ai = match ai {
             Basic => ai_basic(monster_id, tcod, game, objects),
-            Ranged { range } => ai_ranged(monster_id, tcod, game, objects, range),
+            Ranged {
+                range,
+                ammo,
+                cooldown,
+            } => ai_ranged(monster_id, tcod, game, objects, range, ammo, cooldown),
             Confused {
                 previous_ai,
                 num_turns,
             } => ai_confused(monster_id, tcod, game, objects, previous_ai, num_turns),
+            Investigating { previous_ai, x, y } => {
+                ai_investigating(monster_id, tcod, game, objects, previous_ai, x, y)
+            }
+            Searching {
+                previous_ai,
+                x,
+                y,
+                num_turns,
+            } => ai_searching(monster_id, tcod, game, objects, previous_ai, (x, y), num_turns),
+            Fleeing {
+                previous_ai,
+                num_turns,
+            } => ai_fleeing(monster_id, tcod, game, objects, previous_ai, num_turns),
+            Caster {
+                spells,
+                mana,
+                cooldown,
+            } => ai_caster(monster_id, tcod, game, objects, spells, mana, cooldown),
+            Boss {
+                spells,
+                mana,
+                cooldown,
+            } => ai_boss(monster_id, tcod, game, objects, spells, mana, cooldown),
+            Ally { order } => ai_ally(monster_id, game, objects, order),
         };
         objects[monster_id].ai = Some(new_ai);
     }
+
+    // a monster that walked onto a trap sets it off
+    let (x, y) = objects[monster_id].pos();
+    if (x, y) != start && objects[monster_id].alive {
+        if let Some(trap_id) = trap_at(x, y, objects) {
+            spring_trap(trap_id, monster_id, tcod, game, objects);
+        }
+    }
+
+    let moved = objects[monster_id].pos() != start;
+    apply_terrain(monster_id, moved, tcod, game, objects);
 }
 
-fn ai_basic(monster_id: usize, tcod: &mut Tcod, game: &mut Game, objects: &mut [Object]) -> AI {
-    // a basic monster takes its turn. If you can see it, it can see you
-    let (monster_x, monstery_y) = objects[monster_id].pos();
-    if tcod.fov.is_in_fov(monster_x, monstery_y) {
-        if objects[monster_id].distance_to(&objects[PLAYER]) >= 2.0 {
-            // move towards player if far away
-            let (player_x, player_y) = objects[PLAYER].pos();
-            move_towards(monster_id, player_x, player_y, &game.map, objects);
-        } else {
-            // close enough, attack! (if the player is still alive)
-            let (monster, player) = mut_two(monster_id, PLAYER, objects);
-            monster.attack(player, game);
+/// What the ground does to a creature at the end of its turn: slow terrain
+/// costs extra turns, tall grass gets trampled, water puts out flames, fire
+/// and lava burn, and chasms drop whoever steps in to the level below
+fn apply_terrain(id: usize, moved: bool, tcod: &mut Tcod, game: &mut Game, objects: &mut Vec<Object>) {
+    if !objects[id].alive || objects[id].fighter.is_none() {
+        return;
+    }
+    let (x, y) = objects[id].pos();
+    let seen = tcod.is_visible(x, y);
+    let name = objects[id].name.clone();
+    let quick = objects[id].has_affix(Affix::Speed, game);
+    let tile = &mut game.map[x as usize][y as usize];
+    let terrain = tile.terrain;
+
+    if moved {
+        let delay = if quick { 0 } else { terrain.move_cost() - 1 };
+        objects[id].fighter.as_mut().unwrap().delay = delay;
+        if terrain == Terrain::Grass && tile.block_sight {
+            tile.block_sight = false;
+            update_fov_tile(x, y, &game.map, tcod);
+        } else if terrain == Terrain::Door && tile.blocked {
+            tile.blocked = false;
+            tile.block_sight = false;
+            update_fov_tile(x, y, &game.map, tcod);
+            if seen {
+                game.messages.add(format!("The {} opens a door.", name), WHITE);
+            }
         }
     }
-    AI::Basic
+
+    let fighter = objects[id].fighter.as_mut().unwrap();
+    if terrain == Terrain::Water && fighter.burning > 0 {
+        fighter.burning = 0;
+        if seen {
+            game.messages.add(format!("The flames on the {} hiss out.", name), LIGHT_BLUE);
+        }
+    }
+    if fighter.burning > 0 {
+        fighter.burning -= 1;
+        if seen {
+            game.messages.add(format!("The {} burns!", name), ORANGE);
+        }
+        let damage = objects[id].fire_damage(BURN_DAMAGE, game);
+        objects[id].take_damage(damage, game);
+    }
+
+    match terrain {
+        Terrain::Lava if objects[id].alive => {
+            if seen {
+                game.messages.add(format!("The {} is burned by the lava!", name), ORANGE);
+            }
+            objects[id].fighter.as_mut().unwrap().burning = BURN_TURNS;
+            let damage = objects[id].fire_damage(LAVA_DAMAGE, game);
+            objects[id].take_damage(damage, game);
+        }
+        Terrain::Chasm if objects[id].alive => fall_into_chasm(id, seen, tcod, game, objects),
+        _ => {}
+    }
 }
 
-fn ai_ranged(monster_id: usize, tcod: &mut Tcod, game: &mut Game, objects: &mut [Object], range: f32) -> AI {
-    // a basic monster takes its turn. If you can see it, it can see you
-    let (monster_x, monstery_y) = objects[monster_id].pos();
-    if tcod.fov.is_in_fov(monster_x, monstery_y) {
-        if objects[monster_id].distance_to(&objects[PLAYER]) >= range {
-            // move towards player if far away
-            let (player_x, player_y) = objects[PLAYER].pos();
-            move_towards(monster_id, player_x, player_y, &game.map, objects);
-        } else {
-            // close enough, attack! (if the player is still alive)
-            let (monster, player) = mut_two(monster_id, PLAYER, objects);
-            monster.ranged_attack(player, game, range);
+/// The player lands on the next level down, leaving any allies behind.
+/// Monsters are gone for good
+fn fall_into_chasm(id: usize, seen: bool, tcod: &mut Tcod, game: &mut Game, objects: &mut Vec<Object>) {
+    if id == PLAYER {
+        game.messages.add("You fall into the chasm!", RED);
+        game.activity = None;
+        change_level(game.dungeon_level + 1, false, tcod, game, objects);
+        game.messages.add("You land hard on the level below.", RED);
+        objects[PLAYER].take_damage(CHASM_FALL_DAMAGE, game);
+    } else {
+        if seen {
+            game.messages.add(format!("The {} falls into the chasm!", objects[id].name), RED);
         }
+        let monster = &mut objects[id];
+        monster.alive = false;
+        monster.blocks = false;
+        monster.fighter = None;
+        monster.ai = None;
+        game.fallen.push(id);
     }
-    AI::Ranged { range }
 }
 
-fn ai_confused(
-    monster_id: usize, 
-    _tcod: &mut Tcod,
-    game: &mut Game, 
-    objects: &mut [Object],
-    previous_ai: Box<AI>,
-    num_turns: i32,
-) -> AI {
-    if num_turns > 0 {
-        // still confused
-        // move in a random direction, then decreaes number of turns confused
-        move_by(
-            monster_id,
-            rand::thread_rng().gen_range(-1, 2),
-            rand::thread_rng().gen_range(-1, 2),
+/// Let a monster look for the player with its own eyes, waking up or
+/// becoming alert when it notices them. Returns true if the monster is
+/// hunting the player and can see them
+fn monster_sees_player(monster_id: usize, tcod: &Tcod, game: &mut Game, objects: &mut [Object]) -> bool {
+    let fighter = match objects[monster_id].fighter {
+        Some(fighter) => fighter,
+        None => return false,
+    };
+    // neutral monsters pay the player no mind
+    if !game.factions.hostile(objects[monster_id].faction, Faction::Player) {
+        return false;
+    }
+
+    let distance = objects[monster_id].distance_to(&objects[PLAYER]);
+    let in_sight = distance <= fighter.perception as f32
+        && line_of_sight(objects[monster_id].pos(), objects[PLAYER].pos(), &game.map);
+    if !in_sight {
+        return false;
+    }
+    if fighter.alertness == Alertness::Alert {
+        return true;
+    }
+
+    // sharp eyes beat a quiet step, and sleeping monsters rarely notice anything
+    let (player_x, player_y) = objects[PLAYER].pos();
+    let darkness = if tcod.is_lit(player_x, player_y) { 0 } else { DARKNESS_STEALTH_BONUS };
+    let mut chance = NOTICE_BASE_CHANCE
+        + 5 * (fighter.perception - objects[PLAYER].stealth(game) - darkness)
+        - 3 * distance as i32;
+    if fighter.alertness == Alertness::Asleep {
+        chance /= 4;
+    }
+
+    if rand::thread_rng().gen_range(0, 100) < cmp::max(chance, 1) {
+        let monster = &mut objects[monster_id];
+        monster.fighter.as_mut().unwrap().alertness = Alertness::Alert;
+        if tcod.is_visible(monster.x, monster.y) {
+            game.messages.add(format!("The {} notices you!", monster.name), LIGHT_RED);
+        }
+        alert_group(monster_id, objects);
+        true
+    } else {
+        false
+    }
+}
+
+/// Tell the rest of a monster's pack where the player is
+fn alert_group(monster_id: usize, objects: &mut [Object]) {
+    let group_id = match objects[monster_id].group {
+        Some(group) => group.id,
+        None => return,
+    };
+    let (x, y) = objects[PLAYER].pos();
+
+    for (id, member) in objects.iter_mut().enumerate() {
+        if id == monster_id || member.group.map_or(true, |g| g.id != group_id) {
+            continue;
+        }
+        if let Some(fighter) = member.fighter.as_mut() {
+            fighter.alertness = Alertness::Alert;
+            member.ai = investigate(member.ai.take(), x, y);
+        }
+    }
+}
+
+/// The index of the leader of a monster's pack, if it follows one. When
+/// the leader is gone, the follower takes over
+fn pack_leader(monster_id: usize, objects: &mut [Object]) -> Option<usize> {
+    let group = objects[monster_id].group?;
+    if group.leader {
+        return None;
+    }
+
+    let leader_id = objects.iter().position(|o| {
+        o.fighter.is_some() && o.group == Some(Group { id: group.id, leader: true })
+    });
+    if leader_id.is_none() {
+        objects[monster_id].group = Some(Group { id: group.id, leader: true });
+    }
+    leader_id
+}
+
+/// Awake monsters that haven't noticed anyone shuffle around, or keep
+/// close to the leader of their pack
+fn monster_wander(monster_id: usize, game: &Game, objects: &mut [Object]) {
+    let awake = objects[monster_id]
+        .fighter
+        .map_or(false, |f| f.alertness == Alertness::Awake);
+    if !awake {
+        return;
+    }
+
+    if let Some(leader_id) = pack_leader(monster_id, objects) {
+        if objects[monster_id].distance_to(&objects[leader_id]) > 2.0 {
+            let (leader_x, leader_y) = objects[leader_id].pos();
+            move_along_path(monster_id, leader_x, leader_y, &game.map, objects);
+            return;
+        }
+    }
+
+    if rand::random::<f32>() < 0.3 {
+        move_by(
+            monster_id,
+            rand::thread_rng().gen_range(-1, 2),
+            rand::thread_rng().gen_range(-1, 2),
             &game.map,
             objects,
         );
+    }
+}
+
+/// Tiles on a Bresenham line from one point to another, excluding the start
+fn line(from: (i32, i32), to: (i32, i32)) -> Vec<(i32, i32)> {
+    let (mut x, mut y) = from;
+    let dx = (to.0 - x).abs();
+    let dy = -(to.1 - y).abs();
+    let step_x = if x < to.0 { 1 } else { -1 };
+    let step_y = if y < to.1 { 1 } else { -1 };
+    let mut error = dx + dy;
+
+    let mut points = vec![];
+    while (x, y) != to {
+        let doubled = 2 * error;
+        if doubled >= dy {
+            error += dy;
+            x += step_x;
+        }
+        if doubled <= dx {
+            error += dx;
+            y += step_y;
+        }
+        points.push((x, y));
+    }
+    points
+}
+
+/// True if no sight-blocking tile lies between two points
+fn line_of_sight(from: (i32, i32), to: (i32, i32), map: &Map) -> bool {
+    line(from, to)
+        .iter()
+        .filter(|&&pos| pos != to)
+        .all(|&(x, y)| !map[x as usize][y as usize].block_sight)
+}
+
+/// The nearest enemy a monster has noticed, be it the player or a monster
+/// of a hostile faction
+fn choose_target(monster_id: usize, tcod: &Tcod, game: &mut Game, objects: &mut [Object]) -> Option<usize> {
+    let sees_player = monster_sees_player(monster_id, tcod, game, objects);
+    let rival_id = nearest_rival(monster_id, game, objects);
+    match (sees_player, rival_id) {
+        (true, Some(rival_id))
+            if objects[monster_id].distance_to(&objects[rival_id])
+                < objects[monster_id].distance_to(&objects[PLAYER]) =>
+        {
+            Some(rival_id)
+        }
+        (true, _) => Some(PLAYER),
+        (false, rival_id) => rival_id,
+    }
+}
+
+/// Only the player is worth tracking down once out of sight
+fn remember_target(ai: AI, target_id: usize, objects: &[Object]) -> AI {
+    if target_id == PLAYER {
+        remember_player(ai, objects)
+    } else {
+        ai
+    }
+}
+
+fn ai_basic(monster_id: usize, tcod: &mut Tcod, game: &mut Game, objects: &mut [Object]) -> AI {
+    // a basic monster goes for the nearest enemy it has noticed
+    if let Some(target_id) = choose_target(monster_id, tcod, game, objects) {
+        if objects[monster_id].distance_to(&objects[target_id]) >= 2.0 {
+            // move towards the target if far away, with packs spreading out
+            // to surround the player
+            let (target_x, target_y) = objects[target_id].pos();
+            let surrounded = target_id == PLAYER
+                && objects[monster_id].group.is_some()
+                && move_to_surround(monster_id, target_x, target_y, &game.map, objects);
+            if !surrounded && !smash_towards(monster_id, target_x, target_y, tcod, game, objects) {
+                move_towards(monster_id, target_x, target_y, &game.map, objects);
+            }
+        } else {
+            // close enough, attack! (if the target is still alive)
+            let (monster, target) = mut_two(monster_id, target_id, objects);
+            monster.attack(target, game);
+            let (x, y) = objects[monster_id].pos();
+            make_noise(x, y, FIGHT_NOISE_RADIUS, &game.map, objects);
+        }
+        return remember_target(AI::Basic, target_id, objects);
+    }
+    monster_wander(monster_id, game, objects);
+    AI::Basic
+}
+
+fn ai_ally(monster_id: usize, game: &mut Game, objects: &mut [Object], order: Order) -> AI {
+    if let Order::Attack { x, y } = order {
+        // the target may have taken a step since we last looked
+        let faction = objects[monster_id].faction;
+        let target_id = objects
+            .iter()
+            .enumerate()
+            .filter(|&(id, o)| {
+                id != PLAYER
+                    && id != monster_id
+                    && o.fighter.is_some()
+                    && game.factions.hostile(faction, o.faction)
+                    && o.distance(x, y) < 2.0
+            })
+            .min_by_key(|&(_, o)| (o.x - x).abs() + (o.y - y).abs())
+            .map(|(id, _)| id);
+        if let Some(target_id) = target_id {
+            engage(monster_id, target_id, game, objects);
+            let (x, y) = objects[target_id].pos();
+            return AI::Ally {
+                order: Order::Attack { x, y },
+            };
+        }
+        // nothing left to attack, so come back
+        return AI::Ally {
+            order: Order::Follow,
+        };
+    }
+
+    let distance_to_player = objects[monster_id].distance_to(&objects[PLAYER]);
+    let rival_id = nearest_rival(monster_id, game, objects).filter(|&rival_id| match order {
+        // a waiting ally only defends itself
+        Order::Stay => objects[monster_id].distance_to(&objects[rival_id]) < 2.0,
+        _ => objects[PLAYER].distance_to(&objects[rival_id]) <= ALLY_FOLLOW_DISTANCE,
+    });
+
+    if let Some(rival_id) = rival_id {
+        engage(monster_id, rival_id, game, objects);
+    } else if order == Order::Follow && distance_to_player >= 3.0 {
+        let (player_x, player_y) = objects[PLAYER].pos();
+        move_along_path(monster_id, player_x, player_y, &game.map, objects);
+    }
+    AI::Ally { order }
+}
+
+/// Attack the target if it is adjacent, otherwise close in on it
+fn engage(monster_id: usize, target_id: usize, game: &mut Game, objects: &mut [Object]) {
+    if objects[monster_id].distance_to(&objects[target_id]) >= 2.0 {
+        let (target_x, target_y) = objects[target_id].pos();
+        move_along_path(monster_id, target_x, target_y, &game.map, objects);
+    } else {
+        let (monster, target) = mut_two(monster_id, target_id, objects);
+        monster.attack(target, game);
+        let (x, y) = objects[monster_id].pos();
+        make_noise(x, y, FIGHT_NOISE_RADIUS, &game.map, objects);
+    }
+}
+
+/// Find the closest monster of a hostile faction that this one can see
+fn nearest_rival(monster_id: usize, game: &Game, objects: &[Object]) -> Option<usize> {
+    let monster = &objects[monster_id];
+    let perception = monster.fighter.map_or(0, |f| f.perception) as f32;
+    objects
+        .iter()
+        .enumerate()
+        .filter(|&(id, other)| {
+            id != PLAYER
+                && id != monster_id
+                && other.alive
+                && other.fighter.is_some()
+                && game.factions.hostile(monster.faction, other.faction)
+                && monster.distance_to(other) <= perception
+                && line_of_sight(monster.pos(), other.pos(), &game.map)
+        })
+        .min_by(|&(_, a), &(_, b)| {
+            monster
+                .distance_to(a)
+                .partial_cmp(&monster.distance_to(b))
+                .unwrap()
+        })
+        .map(|(id, _)| id)
+}
+
+fn ai_ranged(
+    monster_id: usize,
+    tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut [Object],
+    range: f32,
+    ammo: i32,
+    cooldown: i32,
+) -> AI {
+    let mut ammo = ammo;
+    let cooldown = cmp::max(cooldown - 1, 0);
+
+    // a ranged monster takes its turn, once it has noticed an enemy
+    let target_id = match choose_target(monster_id, tcod, game, objects) {
+        Some(target_id) => target_id,
+        None => {
+            monster_wander(monster_id, game, objects);
+            return AI::Ranged { range, ammo, cooldown };
+        }
+    };
+
+    let (target_x, target_y) = objects[target_id].pos();
+    let distance = objects[monster_id].distance_to(&objects[target_id]);
+    let has_shot = clear_shot(objects[monster_id].pos(), (target_x, target_y), &game.map, objects);
+
+    if ammo == 0 {
+        // nothing left to shoot, so wade in like everyone else
+        if distance >= 2.0 {
+            move_along_path(monster_id, target_x, target_y, &game.map, objects);
+        } else {
+            let (monster, target) = mut_two(monster_id, target_id, objects);
+            monster.attack(target, game);
+        }
+        return remember_target(AI::Ranged { range, ammo, cooldown }, target_id, objects);
+    }
+
+    if distance < 2.0 && step_away(monster_id, target_x, target_y, &game.map, objects) {
+        // backed off to keep its distance
+        return remember_target(AI::Ranged { range, ammo, cooldown }, target_id, objects);
+    }
+
+    let cooldown = if distance <= range && has_shot {
+        if cooldown == 0 {
+            let (monster, target) = mut_two(monster_id, target_id, objects);
+            monster.ranged_attack(target, game, range);
+            ammo -= 1;
+            if ammo == 0 && tcod.is_visible(objects[monster_id].x, objects[monster_id].y) {
+                game.messages.add(
+                    format!("The {} has run out of ammunition!", objects[monster_id].name),
+                    LIGHT_GREY,
+                );
+            }
+            RANGED_COOLDOWN_TURNS
+        } else {
+            // hold position and get ready for the next shot
+            cooldown
+        }
+    } else {
+        // get closer, or around whatever is in the way
+        move_along_path(monster_id, target_x, target_y, &game.map, objects);
+        cooldown
+    };
+    remember_target(AI::Ranged { range, ammo, cooldown }, target_id, objects)
+}
+
+fn ai_caster(
+    monster_id: usize,
+    tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut Vec<Object>,
+    spells: Vec<Spell>,
+    mana: i32,
+    cooldown: i32,
+) -> AI {
+    let mut mana = cmp::min(mana + 1, CASTER_MAX_MANA);
+    let mut cooldown = cmp::max(cooldown - 1, 0);
+
+    // a caster takes its turn, once it has noticed an enemy
+    let target_id = match choose_target(monster_id, tcod, game, objects) {
+        Some(target_id) => target_id,
+        None => {
+            monster_wander(monster_id, game, objects);
+            return AI::Caster { spells, mana, cooldown };
+        }
+    };
+
+    let spell = if cooldown == 0 {
+        choose_spell(monster_id, target_id, &spells, mana, game, objects)
+    } else {
+        None
+    };
+
+    if let Some((spell, spell_target_id)) = spell {
+        cast_monster_spell(monster_id, spell, spell_target_id, game, objects);
+        mana -= spell.mana_cost();
+        cooldown = CASTER_COOLDOWN_TURNS;
+    } else {
+        // keep out of reach while gathering power
+        let (target_x, target_y) = objects[target_id].pos();
+        let distance = objects[monster_id].distance_to(&objects[target_id]);
+        if distance < 2.0 {
+            if !step_away(monster_id, target_x, target_y, &game.map, objects) {
+                let (monster, target) = mut_two(monster_id, target_id, objects);
+                monster.attack(target, game);
+            }
+        } else if distance > CASTER_RANGE {
+            move_along_path(monster_id, target_x, target_y, &game.map, objects);
+        }
+    }
+    remember_target(AI::Caster { spells, mana, cooldown }, target_id, objects)
+}
+
+fn ai_boss(
+    monster_id: usize,
+    tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut Vec<Object>,
+    spells: Vec<Spell>,
+    mana: i32,
+    cooldown: i32,
+) -> AI {
+    let mut mana = cmp::min(mana + 1, CASTER_MAX_MANA);
+    let mut cooldown = cmp::max(cooldown - 1, 0);
+
+    // bosses hold their ground until an enemy shows up
+    let target_id = match choose_target(monster_id, tcod, game, objects) {
+        Some(target_id) => target_id,
+        None => return AI::Boss { spells, mana, cooldown },
+    };
+
+    let spell = if cooldown == 0 {
+        choose_spell(monster_id, target_id, &spells, mana, game, objects)
+    } else {
+        None
+    };
+    if let Some((spell, spell_target_id)) = spell {
+        cast_monster_spell(monster_id, spell, spell_target_id, game, objects);
+        mana -= spell.mana_cost();
+        cooldown = CASTER_COOLDOWN_TURNS;
+    } else {
+        // otherwise wade in like any other monster
+        ai_basic(monster_id, tcod, game, objects);
+    }
+    remember_target(AI::Boss { spells, mana, cooldown }, target_id, objects)
+}
+
+fn is_boss(object: &Object) -> bool {
+    matches!(object.ai, Some(AI::Boss { .. }))
+}
+
+/// Pick the spell that suits the situation best against the given enemy,
+/// with whoever the spell should land on
+fn choose_spell(
+    monster_id: usize,
+    enemy_id: usize,
+    spells: &[Spell],
+    mana: i32,
+    game: &Game,
+    objects: &[Object],
+) -> Option<(Spell, usize)> {
+    let caster = &objects[monster_id];
+    let can_cast = |spell: Spell| spells.contains(&spell) && mana >= spell.mana_cost();
+
+    // patch up the most badly hurt ally in sight, or itself
+    if can_cast(Spell::HealAlly) {
+        if caster_is_hurt(caster, game) {
+            return Some((Spell::HealAlly, monster_id));
+        }
+        let hurt_ally = objects
+            .iter()
+            .enumerate()
+            .filter(|&(id, o)| {
+                id != PLAYER
+                    && o.ai.is_some()
+                    && !game.factions.hostile(caster.faction, o.faction)
+                    && caster.distance_to(o) <= CASTER_RANGE
+                    && line_of_sight(caster.pos(), o.pos(), &game.map)
+            })
+            .filter_map(|(id, o)| o.fighter.map(|f| (id, f.hp * 100 / cmp::max(o.max_hp(game), 1))))
+            .filter(|&(_, hp_percent)| hp_percent < 50)
+            .min_by_key(|&(_, hp_percent)| hp_percent);
+        if let Some((ally_id, _)) = hurt_ally {
+            return Some((Spell::HealAlly, ally_id));
+        }
+    }
+
+    let enemy = &objects[enemy_id];
+    let in_range = caster.distance_to(enemy) <= CASTER_RANGE
+        && clear_shot(caster.pos(), enemy.pos(), &game.map, objects);
+
+    // call for help when facing the enemy alone
+    if can_cast(Spell::Summon) {
+        let allies_near = objects
+            .iter()
+            .enumerate()
+            .filter(|&(id, o)| {
+                id != PLAYER
+                    && o.ai.is_some()
+                    && o.fighter.is_some()
+                    && !game.factions.hostile(caster.faction, o.faction)
+                    && caster.distance_to(o) <= CASTER_RANGE
+            })
+            .count();
+        if allies_near < 2 {
+            return Some((Spell::Summon, monster_id));
+        }
+    }
+
+    // raise the fallen to fight again
+    if can_cast(Spell::RaiseDead) {
+        let corpse = objects.iter().enumerate().find(|&(id, o)| {
+            id != PLAYER
+                && o.name.starts_with("remains of")
+                && caster.distance_to(o) <= CASTER_RANGE
+                && !is_blocked(o.x, o.y, &game.map, objects)
+        });
+        if let Some((corpse_id, _)) = corpse {
+            return Some((Spell::RaiseDead, corpse_id));
+        }
+    }
+
+    if !in_range {
+        return None;
+    }
+
+    let enemy_confused = enemy.fighter.map_or(false, |f| f.confused > 0)
+        || matches!(enemy.ai, Some(AI::Confused { .. }));
+    if can_cast(Spell::Confuse) && !enemy_confused && rand::random::<f32>() < 0.3 {
+        return Some((Spell::Confuse, enemy_id));
+    }
+    if can_cast(Spell::Bolt) {
+        return Some((Spell::Bolt, enemy_id));
+    }
+    None
+}
+
+/// True if the caster itself needs healing
+fn caster_is_hurt(caster: &Object, game: &Game) -> bool {
+    caster.fighter.map_or(false, |f| f.hp * 2 < caster.max_hp(game))
+}
+
+/// Cast a monster's spell. Spells aimed at the player go through the
+/// same effects as the player's own scrolls
+fn cast_monster_spell(
+    caster_id: usize,
+    spell: Spell,
+    target_id: usize,
+    game: &mut Game,
+    objects: &mut Vec<Object>,
+) {
+    let caster_name = objects[caster_id].name.clone();
+    match spell {
+        Spell::HealAlly => {
+            game.messages.add(
+                format!("The {} chants, and the {}'s wounds close!", caster_name, objects[target_id].name),
+                LIGHT_VIOLET,
+            );
+            let target = &mut objects[target_id];
+            let max_hp = target.max_hp(game);
+            if let Some(fighter) = target.fighter.as_mut() {
+                fighter.hp = cmp::min(fighter.hp + SPELL_HEAL_AMOUNT, max_hp);
+            }
+        }
+        Spell::Confuse => {
+            game.messages.add(format!("The {} casts a spell of confusion!", caster_name), LIGHT_CYAN);
+            confuse(target_id, game, objects);
+        }
+        Spell::Summon => {
+            // call a broo out of thin air next to the caster
+            let (x, y) = objects[caster_id].pos();
+            let free_tile = (-1..=1)
+                .flat_map(|dx| (-1..=1).map(move |dy| (x + dx, y + dy)))
+                .find(|&(nx, ny)| !is_blocked(nx, ny, &game.map, objects));
+            if let Some((nx, ny)) = free_tile {
+                game.messages.add(
+                    format!("The {} summons a Broo from the shadows!", caster_name),
+                    LIGHT_RED,
+                );
+                let mut broo = create_monster("broo", nx, ny);
+                broo.fighter.as_mut().unwrap().alertness = Alertness::Alert;
+                broo.ai = Some(remember_player(AI::Basic, objects));
+                broo.group = objects[caster_id].group.map(|g| Group { leader: false, ..g });
+                objects.push(broo);
+            }
+        }
+        Spell::RaiseDead => {
+            let (x, y) = objects[target_id].pos();
+            game.messages.add(
+                format!("The {} raises the {} as a skeleton!", caster_name, objects[target_id].name),
+                LIGHT_PURPLE,
+            );
+            let mut skeleton = create_monster("skeleton", x, y);
+            skeleton.ai = Some(remember_player(AI::Basic, objects));
+            objects[target_id] = skeleton;
+        }
+        Spell::Bolt => {
+            game.messages.add(format!("The {} hurls a bolt of lightning!", caster_name), LIGHT_BLUE);
+            if let Some(xp) = lightning_bolt(target_id, SPELL_BOLT_DAMAGE, game, objects) {
+                if let Some(fighter) = objects[caster_id].fighter.as_mut() {
+                    fighter.xp += xp;
+                }
+            }
+        }
+    }
+}
+
+/// True if nothing stands between two points to stop a missile
+fn clear_shot(from: (i32, i32), to: (i32, i32), map: &Map, objects: &[Object]) -> bool {
+    line(from, to)
+        .iter()
+        .filter(|&&pos| pos != to)
+        .all(|&(x, y)| !map[x as usize][y as usize].block_sight && !is_blocked(x, y, map, objects))
+}
+
+/// Step to the free neighbouring tile furthest from a position. Returns
+/// false if there is nowhere to go that is further away
+fn step_away(id: usize, x: i32, y: i32, map: &Map, objects: &mut [Object]) -> bool {
+    let (own_x, own_y) = objects[id].pos();
+    let mut best_distance = objects[id].distance(x, y);
+    let mut best_step = None;
+    for dx in -1..=1 {
+        for dy in -1..=1 {
+            let (nx, ny) = (own_x + dx, own_y + dy);
+            if is_blocked(nx, ny, map, objects) {
+                continue;
+            }
+            let distance = (((nx - x).pow(2) + (ny - y).pow(2)) as f32).sqrt();
+            if distance > best_distance {
+                best_distance = distance;
+                best_step = Some((nx, ny));
+            }
+        }
+    }
+    match best_step {
+        Some((nx, ny)) => {
+            objects[id].set_pos(nx, ny);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Remember where the player was last seen, so the monster can follow
+/// them once they break line of sight
+fn remember_player(ai: AI, objects: &[Object]) -> AI {
+    let (x, y) = objects[PLAYER].pos();
+    AI::Investigating {
+        previous_ai: Box::new(ai),
+        x,
+        y,
+    }
+}
+
+fn ai_investigating(
+    monster_id: usize,
+    tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut Vec<Object>,
+    previous_ai: Box<AI>,
+    x: i32,
+    y: i32,
+) -> AI {
+    if monster_sees_player(monster_id, tcod, game, objects) {
+        // go after the player right away
+        objects[monster_id].ai = Some(*previous_ai);
+        ai_take_turn(monster_id, tcod, game, objects);
+        return objects[monster_id].ai.take().unwrap_or(AI::Basic);
+    }
+
+    if objects[monster_id].pos() != (x, y) && move_along_path(monster_id, x, y, &game.map, objects) {
+        AI::Investigating { previous_ai, x, y }
+    } else {
+        // arrived, or no way to get there: have a look around
+        AI::Searching {
+            previous_ai,
+            x,
+            y,
+            num_turns: SEARCH_TURNS,
+        }
+    }
+}
+
+fn ai_searching(
+    monster_id: usize,
+    tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut Vec<Object>,
+    previous_ai: Box<AI>,
+    (x, y): (i32, i32),
+    num_turns: i32,
+) -> AI {
+    if monster_sees_player(monster_id, tcod, game, objects) {
+        objects[monster_id].ai = Some(*previous_ai);
+        ai_take_turn(monster_id, tcod, game, objects);
+        return objects[monster_id].ai.take().unwrap_or(AI::Basic);
+    }
+
+    if num_turns > 0 {
+        // poke around, but don't stray far from where the player was lost
+        let (monster_x, monster_y) = objects[monster_id].pos();
+        let dx = rand::thread_rng().gen_range(-1, 2);
+        let dy = rand::thread_rng().gen_range(-1, 2);
+        if (monster_x + dx - x).abs() <= SEARCH_RADIUS && (monster_y + dy - y).abs() <= SEARCH_RADIUS {
+            move_by(monster_id, dx, dy, &game.map, objects);
+        }
+        AI::Searching {
+            previous_ai,
+            x,
+            y,
+            num_turns: num_turns - 1,
+        }
+    } else {
+        // give up and calm down
+        let monster = &mut objects[monster_id];
+        if let Some(fighter) = monster.fighter.as_mut() {
+            fighter.alertness = Alertness::Awake;
+        }
+        if tcod.is_visible(monster.x, monster.y) {
+            game.messages.add(format!("The {} gives up the search.", monster.name), WHITE);
+        }
+        *previous_ai
+    }
+}
+
+fn ai_fleeing(
+    monster_id: usize,
+    tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut [Object],
+    previous_ai: Box<AI>,
+    num_turns: i32,
+) -> AI {
+    let threat_id = choose_target(monster_id, tcod, game, objects);
+    let num_turns = if threat_id.is_some() { 0 } else { num_turns + 1 };
+    let threat_id = threat_id.unwrap_or(PLAYER);
+
+    let monster = &objects[monster_id];
+    let hp = monster.fighter.map_or(0, |f| f.hp);
+    if hp * 100 >= monster.max_hp(game) * RECOVER_HP_PERCENT || num_turns >= FLEE_CALM_TURNS {
+        if tcod.is_visible(monster.x, monster.y) {
+            game.messages.add(format!("The {} regains its courage!", monster.name), LIGHT_RED);
+        }
+        return *previous_ai;
+    }
+
+    // roll downhill on a map that leads away from whatever it is running
+    // from, which prefers open space over dead ends
+    let flee_map = flee_map(objects[threat_id].pos(), &game.map);
+    let (x, y) = objects[monster_id].pos();
+    let mut best_value = flee_map[x as usize][y as usize];
+    let mut best_pos = (x, y);
+    for dx in -1..=1 {
+        for dy in -1..=1 {
+            let (nx, ny) = (x + dx, y + dy);
+            let faction = objects[monster_id].faction;
+            if is_blocked(nx, ny, &game.map, objects) || is_hazard(nx, ny, faction, &game.map, objects) {
+                continue;
+            }
+            let value = flee_map[nx as usize][ny as usize];
+            if value.is_some() && (best_value.is_none() || value < best_value) {
+                best_value = value;
+                best_pos = (nx, ny);
+            }
+        }
+    }
+
+    if best_pos != (x, y) {
+        objects[monster_id].set_pos(best_pos.0, best_pos.1);
+    } else if objects[monster_id].distance_to(&objects[threat_id]) < 2.0 {
+        // cornered, so fight back
+        let (monster, threat) = mut_two(monster_id, threat_id, objects);
+        monster.attack(threat, game);
+    }
+
+    AI::Fleeing {
+        previous_ai,
+        num_turns,
+    }
+}
+
+/// Monsters that watched an ally die this turn may lose their nerve
+fn check_morale_after_deaths(game: &mut Game, objects: &mut [Object]) {
+    let deaths = std::mem::take(&mut game.recent_deaths);
+    for (x, y) in deaths {
+        for (id, monster) in objects.iter_mut().enumerate() {
+            let saw_it = monster.fighter.map_or(false, |f| {
+                monster.distance(x, y) <= f.perception as f32
+                    && line_of_sight(monster.pos(), (x, y), &game.map)
+            });
+            if id != PLAYER && saw_it {
+                monster.check_morale(ALLY_DEATH_FEAR, game);
+            }
+        }
+    }
+}
+
+fn ai_confused(
+    monster_id: usize, 
+    _tcod: &mut Tcod,
+    game: &mut Game, 
+    objects: &mut [Object],
+    previous_ai: Box<AI>,
+    num_turns: i32,
+) -> AI {
+    if num_turns > 0 {
+        // still confused
+        // move in a random direction, hitting whoever is in the way,
+        // then decreaes number of turns confused
+        let dx = rand::thread_rng().gen_range(-1, 2);
+        let dy = rand::thread_rng().gen_range(-1, 2);
+        let (x, y) = (objects[monster_id].x + dx, objects[monster_id].y + dy);
+        let target_id = objects
+            .iter()
+            .position(|object| object.fighter.is_some() && object.alive && object.pos() == (x, y));
+        match target_id {
+            Some(target_id) if target_id != monster_id => {
+                let (monster, target) = mut_two(monster_id, target_id, objects);
+                monster.attack(target, game);
+            }
+            _ => move_by(monster_id, dx, dy, &game.map, objects),
+        }
         AI::Confused {
             previous_ai: previous_ai,
             num_turns: num_turns - 1,
@@ -1132,27 +3999,145 @@ fn ai_confused(
     }
 }
 
-/// Mutably borrow two *separate* elements from a given slice.
-/// Panics when the indexes are equal or out of bounds.
-fn mut_two<T>(first_index: usize, second_index: usize, items: &mut [T]) -> (&mut T, &mut T) {
-    assert!(first_index != second_index);
-    let split_at_index = cmp::max(first_index, second_index);
-    let (first_slice, second_slice) = items.split_at_mut(split_at_index);
-    if first_index < second_index {
-        (&mut first_slice[first_index], &mut second_slice[0])
-    } else {
-        (&mut second_slice[0], &mut first_slice[second_index])
-    }
+/// Mutably borrow two *separate* elements from a given slice.
+/// Panics when the indexes are equal or out of bounds.
+fn mut_two<T>(first_index: usize, second_index: usize, items: &mut [T]) -> (&mut T, &mut T) {
+    assert!(first_index != second_index);
+    let split_at_index = cmp::max(first_index, second_index);
+    let (first_slice, second_slice) = items.split_at_mut(split_at_index);
+    if first_index < second_index {
+        (&mut first_slice[first_index], &mut second_slice[0])
+    } else {
+        (&mut second_slice[0], &mut first_slice[second_index])
+    }
+}
+
+fn move_by(id: usize, dx: i32, dy: i32, map: &Map, objects: &mut [Object]) {
+    let (x, y) = objects[id].pos();
+    // monsters step around hazards, the player goes where told
+    let avoid = id != PLAYER && is_hazard(x + dx, y + dy, objects[id].faction, map, objects);
+    let open = if id == PLAYER {
+        !is_blocked(x + dx, y + dy, map, objects)
+    } else {
+        monster_can_enter(x + dx, y + dy, map, objects)
+    };
+    if open && !avoid {
+        objects[id].set_pos(x + dx, y + dy);
+    }
+}
+
+/// Take one step along the shortest path to a position, walking around
+/// walls and other objects. Returns false if there is no way there
+fn move_along_path(id: usize, x: i32, y: i32, map: &Map, objects: &mut [Object]) -> bool {
+    let start = objects[id].pos();
+    let faction = objects[id].faction;
+    let path = find_path(
+        start,
+        map,
+        |px, py| monster_can_enter(px, py, map, objects) && !is_hazard(px, py, faction, map, objects),
+        |gx, gy| (gx, gy) == (x, y),
+    );
+    match path {
+        Some(path) if !path.is_empty() => {
+            let (next_x, next_y) = path[0];
+            if !monster_can_enter(next_x, next_y, map, objects) {
+                return false;
+            }
+            objects[id].set_pos(next_x, next_y);
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Head for the nearest free tile next to a target, going around anyone
+/// in the way, so that a pack surrounds its prey instead of queuing up in
+/// a corridor. Returns false if that would take too long a detour
+fn move_to_surround(id: usize, target_x: i32, target_y: i32, map: &Map, objects: &mut [Object]) -> bool {
+    let start = objects[id].pos();
+    let faction = objects[id].faction;
+    let path = find_path(
+        start,
+        map,
+        |x, y| !is_blocked(x, y, map, objects) && !is_hazard(x, y, faction, map, objects),
+        |x, y| {
+            (x - target_x).abs() <= 1
+                && (y - target_y).abs() <= 1
+                && (x, y) != (target_x, target_y)
+                && !is_blocked(x, y, map, objects)
+        },
+    );
+
+    let direct = cmp::max((target_x - start.0).abs(), (target_y - start.1).abs());
+    match path {
+        Some(path) if !path.is_empty() && (path.len() as i32) <= direct + FLANK_MAX_DETOUR => {
+            let (x, y) = path[0];
+            objects[id].set_pos(x, y);
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Make a noise at a position. Monsters that can hear it, counting the
+/// steps a sound takes around walls rather than the straight distance,
+/// come to investigate
+fn make_noise(x: i32, y: i32, radius: i32, map: &Map, objects: &mut [Object]) {
+    let distances = path_distances((x, y), radius, map);
+
+    for object in objects.iter_mut() {
+        let fighter = match object.fighter.as_mut() {
+            Some(fighter) => fighter,
+            None => continue,
+        };
+        let distance = match distances[object.x as usize][object.y as usize] {
+            Some(distance) => distance,
+            None => continue,
+        };
+        // sleepers only wake up to loud noises close by
+        if fighter.alertness == Alertness::Asleep {
+            if distance > radius / 4 {
+                continue;
+            }
+            fighter.alertness = Alertness::Awake;
+        }
+
+        object.ai = investigate(object.ai.take(), x, y);
+    }
+}
+
+/// True for creatures fighting on the player's side
+fn is_ally(object: &Object) -> bool {
+    object.faction == Faction::Player && object.ai.is_some()
 }
 
-fn move_by(id: usize, dx: i32, dy: i32, map: &Map, objects: &mut [Object]) {
-    let (x, y) = objects[id].pos();
-    if !is_blocked(x + dx, y + dy, map, objects) {
-        objects[id].set_pos(x + dx, y + dy);
+/// Send a monster off to investigate a position, unless it's too busy
+fn investigate(ai: Option<AI>, x: i32, y: i32) -> Option<AI> {
+    match ai {
+        Some(AI::Investigating { previous_ai, .. }) | Some(AI::Searching { previous_ai, .. }) => {
+            Some(AI::Investigating { previous_ai, x, y })
+        }
+        // too busy to care
+        Some(ai @ AI::Confused { .. }) | Some(ai @ AI::Fleeing { .. }) | Some(ai @ AI::Ally { .. }) => {
+            Some(ai)
+        }
+        Some(ai) => Some(AI::Investigating {
+            previous_ai: Box::new(ai),
+            x,
+            y,
+        }),
+        None => None,
     }
 }
 
 fn player_move_or_attack(dx: i32, dy: i32, game: &mut Game, objects: &mut [Object], tcod: &mut Tcod) {
+    // a confused player stumbles in a random direction
+    let (dx, dy) = if objects[PLAYER].fighter.map_or(false, |f| f.confused > 0) {
+        (rand::thread_rng().gen_range(-1, 2), rand::thread_rng().gen_range(-1, 2))
+    } else {
+        (dx, dy)
+    };
+
     // the coordinates the player is moving to/attacking
     let x = objects[PLAYER].x + dx;
     let y = objects[PLAYER].y + dy;
@@ -1161,35 +4146,521 @@ fn player_move_or_attack(dx: i32, dy: i32, game: &mut Game, objects: &mut [Objec
     let cut = rodio::Decoder::new(BufReader::new(File::open("knifeSlice.ogg").unwrap())).unwrap();
 
     // try to find an attackable object there
+    // (a stumbling player may stay put, which is no reason to hit themselves)
     let target_id = objects
         .iter()
-        .position(|object| object.fighter.is_some() && object.pos() == (x, y));
+        .position(|object| object.fighter.is_some() && object.pos() == (x, y))
+        .filter(|&id| id != PLAYER);
 
     // attack if target found, move otherwise
     match target_id {
+        Some(target_id) if is_ally(&objects[target_id]) => {
+            // swap places with allies rather than hitting them
+            let (player_x, player_y) = objects[PLAYER].pos();
+            objects[target_id].set_pos(player_x, player_y);
+            objects[PLAYER].set_pos(x, y);
+            tcod.sink.append(footstep);
+        }
+        Some(target_id) if is_for_hire(&objects[target_id], game) => {
+            offer_hire(target_id, game, objects, tcod);
+        }
         Some(target_id) => {
             let (player, target) = mut_two(PLAYER, target_id, objects);
             player.attack(target, game);
             tcod.sink.append(cut);
+            make_noise(x, y, FIGHT_NOISE_RADIUS, &game.map, objects);
+        }
+        None if is_diggable(x, y, &game.map) && digging_power(game).is_some() => {
+            game.messages.add("You start digging into the wall.", WHITE);
+            dig(x, y, tcod, game, objects);
         }
         None => {
+            let tile = &mut game.map[x as usize][y as usize];
+            if tile.terrain == Terrain::Door && tile.blocked {
+                // push the door open and step through
+                tile.blocked = false;
+                tile.block_sight = false;
+                update_fov_tile(x, y, &game.map, tcod);
+                game.messages.add("You open the door.", WHITE);
+                make_noise(x, y, DOOR_NOISE_RADIUS, &game.map, objects);
+            }
             move_by(PLAYER, dx, dy, &game.map, objects);
             tcod.sink.append(footstep);
+            let (x, y) = objects[PLAYER].pos();
+            make_noise(x, y, WALK_NOISE_RADIUS, &game.map, objects);
+
+            if let Some(trap_id) = trap_at(x, y, objects) {
+                spring_trap(trap_id, PLAYER, tcod, game, objects);
+            }
         }
     }
 }
 
+/// Sellswords the player has not yet hired, or given reason to fight
+fn is_for_hire(object: &Object, game: &Game) -> bool {
+    object.faction == Faction::Sellswords && !game.factions.hostile(object.faction, Faction::Player)
+}
+
+fn offer_hire(id: usize, game: &mut Game, objects: &mut [Object], tcod: &mut Tcod) {
+    let header = format!("Hire the {}?\n", objects[id].name);
+    if menu(&header, &["Yes", "No"], INVENTORY_WIDTH, &mut tcod.root) != Some(0) {
+        return;
+    }
+    let sellsword = &mut objects[id];
+    sellsword.faction = Faction::Player;
+    sellsword.ai = Some(AI::Ally { order: Order::Follow });
+    game.messages.add(format!("The {} joins you.", sellsword.name), LIGHT_SKY);
+}
+
 fn move_towards(id: usize, target_x: i32, target_y: i32, map: &Map, objects: &mut [Object]) {
+    let (dx, dy) = step_towards(objects[id].pos(), (target_x, target_y));
+    move_by(id, dx, dy, map, objects);
+}
+
+/// The single step from one position that heads most directly to another
+fn step_towards(from: (i32, i32), to: (i32, i32)) -> (i32, i32) {
     // vector from this object to the target and distance
-    let dx = target_x - objects[id].x;
-    let dy = target_y - objects[id].y;
+    let dx = to.0 - from.0;
+    let dy = to.1 - from.1;
     let distance = ((dx.pow(2) + dy.pow(2)) as f32).sqrt();
 
     // normalize to length 1 (preserving direction), then round it and
     // convert to integer so the movement is restricted to map grig
     let dx = (dx as f32 / distance).round() as i32;
     let dy = (dy as f32 / distance).round() as i32;
-    move_by(id, dx, dy, map, objects);
+    (dx, dy)
+}
+
+/// Walls can be dug through, except for the edge of the map
+fn is_diggable(x: i32, y: i32, map: &Map) -> bool {
+    let (width, height) = map_size(map);
+    let inside = x > 0 && y > 0 && x < width - 1 && y < height - 1;
+    inside && map[x as usize][y as usize].terrain == Terrain::Wall
+}
+
+/// Wear down a wall. Returns true once it has taken enough to give way
+fn damage_wall(x: i32, y: i32, amount: i32, map: &mut Map) -> bool {
+    if !is_diggable(x, y, map) {
+        return false;
+    }
+    let tile = &mut map[x as usize][y as usize];
+    tile.damage += amount;
+    tile.damage >= WALL_STRENGTH
+}
+
+/// Replace a broken wall with what is left of it, which the player still
+/// remembers if they had seen the wall
+fn break_wall(x: i32, y: i32, rubble: Tile, map: &mut Map, tcod: &mut Tcod) {
+    let explored = map[x as usize][y as usize].explored;
+    map[x as usize][y as usize] = Tile { explored, ..rubble };
+    update_fov_tile(x, y, map, tcod);
+}
+
+/// A torch held in the off hand, its charges being the fuel left
+fn create_torch(x: i32, y: i32) -> Object {
+    let mut torch = Object::new(x, y, '/', ORANGE, "torch".to_string(), false);
+    torch.item = Some(Item::Torch);
+    torch.equipment = Some(Equipment{ equipped: false, slot: Slot::LeftHand, power_bonus: 0, defense_bonus: 0, max_hp_bonus: 0, range: 0, damage: 0, charges: TORCH_FUEL, weight: 1, enchantment: 0, affix: None, cursed: false, identified: true});
+    torch
+}
+
+/// Inventory index of the torch the player is holding, if it is lit
+fn lit_torch(game: &Game) -> Option<usize> {
+    game.inventory.iter().position(|item| {
+        item.item == Some(Item::Torch) && item.equipment.map_or(false, |e| e.equipped)
+    })
+}
+
+/// Spend a turn's worth of the player's torch, which goes out when empty
+fn burn_torch(game: &mut Game) {
+    let id = match lit_torch(game) {
+        Some(id) => id,
+        None => return,
+    };
+    let equipment = game.inventory[id].equipment.as_mut().unwrap();
+    equipment.charges -= 1;
+    if equipment.charges == TORCH_LOW_FUEL {
+        game.messages.add("Your torch is burning low.", ORANGE);
+    } else if equipment.charges <= 0 {
+        game.inventory.remove(id);
+        game.messages.add("Your torch burns out!", RED);
+    }
+}
+
+/// Everything giving off light on the level: objects carrying a light,
+/// burning creatures, the player's torch and glowing lava
+fn light_sources(game: &Game, objects: &[Object]) -> Vec<((i32, i32), Light)> {
+    let mut sources: Vec<_> = objects
+        .iter()
+        .filter_map(|object| {
+            let burning = object.fighter.map_or(false, |f| f.burning > 0);
+            let light = if burning {
+                Some(Light { radius: BURNING_LIGHT_RADIUS, intensity: 1.0, color: FIRE_LIGHT })
+            } else {
+                object.light
+            };
+            light.map(|light| (object.pos(), light))
+        })
+        .collect();
+    if objects[PLAYER].alive && lit_torch(game).is_some() {
+        let torch = Light { radius: TORCH_RADIUS, intensity: 1.0, color: TORCH_LIGHT };
+        sources.push((objects[PLAYER].pos(), torch));
+    }
+
+    let (width, height) = map_size(&game.map);
+    for x in 0..width {
+        for y in 0..height {
+            if game.map[x as usize][y as usize].terrain == Terrain::Lava {
+                let glow = Light { radius: LAVA_GLOW_RADIUS, intensity: LAVA_GLOW_INTENSITY, color: LAVA_LIGHT };
+                sources.push(((x, y), glow));
+            }
+        }
+    }
+    sources
+}
+
+/// How bright a mix of colored light is, taking its strongest channel
+fn brightness(light: [f32; 3]) -> f32 {
+    light[0].max(light[1]).max(light[2])
+}
+
+/// Light fades smoothly from full strength at the source to nothing just
+/// past its radius
+fn falloff(distance: f32, radius: i32) -> f32 {
+    let f = (1.0 - distance / (radius + 1) as f32).max(0.0);
+    f * f * (3.0 - 2.0 * f)
+}
+
+/// Add up the colored light falling on each tile, then work out which tiles
+/// the player can actually see: lit ones in line of sight, and anything
+/// adjacent
+fn compute_lighting(tcod: &mut Tcod, game: &Game, objects: &[Object]) {
+    let (width, height) = map_size(&game.map);
+    tcod.light = vec![vec![[0.0; 3]; height as usize]; width as usize];
+
+    let setting = tcod.options.fov;
+    let mut lighting = std::mem::take(&mut tcod.light);
+
+    for (source, light) in light_sources(game, objects) {
+        let color = [
+            light.color.r as f32 / 255.0,
+            light.color.g as f32 / 255.0,
+            light.color.b as f32 / 255.0,
+        ];
+        compute_fov(&mut tcod.light_fov, &game.map, setting, source, light.radius, true, |x, y| {
+            let dx = (x - source.0) as f32;
+            let dy = (y - source.1) as f32;
+            let strength = light.intensity * falloff((dx * dx + dy * dy).sqrt(), light.radius);
+            let tile_light = &mut lighting[x as usize][y as usize];
+            for (channel, tint) in tile_light.iter_mut().zip(color.iter()) {
+                *channel = (*channel + strength * tint).min(1.0);
+            }
+        });
+    }
+    tcod.light = lighting;
+
+    // the player sees as far as the light goes
+    let player = objects[PLAYER].pos();
+    let mut sight = vec![vec![false; height as usize]; width as usize];
+    let light_walls = tcod.options.light_walls;
+    compute_fov(&mut tcod.fov, &game.map, setting, player, 0, light_walls, |x, y| {
+        sight[x as usize][y as usize] = true;
+    });
+
+    for (x, column) in sight.iter_mut().enumerate() {
+        for (y, seen) in column.iter_mut().enumerate() {
+            let adjacent = (x as i32 - player.0).abs() <= 1 && (y as i32 - player.1).abs() <= 1;
+            let lit = brightness(tcod.light[x][y]) >= MIN_LIGHT;
+            *seen = *seen && (adjacent || lit);
+        }
+    }
+    tcod.visible = sight;
+}
+
+/// Work out what can be seen from `origin` with the chosen FOV algorithm,
+/// calling `reveal` for each tile in view. libtcod's algorithms run on the
+/// given FOV map, our own only needs the tiles
+fn compute_fov<F: FnMut(i32, i32)>(
+    fov_map: &mut FovMap,
+    map: &Map,
+    setting: FovSetting,
+    origin: (i32, i32),
+    radius: i32,
+    light_walls: bool,
+    mut reveal: F,
+) {
+    let algorithm = match setting.algorithm() {
+        Some(algorithm) => algorithm,
+        None => {
+            let is_transparent = |x, y| in_map(x, y, map) && !map[x as usize][y as usize].block_sight;
+            let in_bounds = |x, y| in_map(x, y, map);
+            fov::compute_fov(origin, radius, light_walls, is_transparent, |x, y| {
+                if in_bounds(x, y) {
+                    reveal(x, y);
+                }
+            });
+            return;
+        }
+    };
+
+    fov_map.compute_fov(origin.0, origin.1, radius, light_walls, algorithm);
+    let (width, height) = map_size(map);
+    let (min_x, max_x, min_y, max_y) = if radius > 0 {
+        (
+            (origin.0 - radius).max(0),
+            (origin.0 + radius + 1).min(width),
+            (origin.1 - radius).max(0),
+            (origin.1 + radius + 1).min(height),
+        )
+    } else {
+        (0, width, 0, height)
+    };
+    for x in min_x..max_x {
+        for y in min_y..max_y {
+            if fov_map.is_in_fov(x, y) {
+                reveal(x, y);
+            }
+        }
+    }
+}
+
+/// How a color is remembered once out of sight: washed out and dim
+fn remembered(color: Color) -> Color {
+    let grey = 0.3 * color.r as f32 + 0.59 * color.g as f32 + 0.11 * color.b as f32;
+    let channel = |c: u8| {
+        let c = c as f32 + (grey - c as f32) * MEMORY_DESATURATION;
+        (c * MEMORY_BRIGHTNESS) as u8
+    };
+    Color { r: channel(color.r), g: channel(color.g), b: channel(color.b) }
+}
+
+/// A color as seen under some light: tinted by the light's hue, and fading
+/// into its remembered look as the light gets dim
+fn lit(color: Color, light: [f32; 3]) -> Color {
+    let memory = remembered(color);
+    let strength = brightness(light);
+    if strength <= 0.0 {
+        return memory;
+    }
+    let channel = |c: u8, m: u8, l: f32| {
+        let tinted = c as f32 * l / strength;
+        (m as f32 + (tinted - m as f32) * strength) as u8
+    };
+    Color {
+        r: channel(color.r, memory.r, light[0]),
+        g: channel(color.g, memory.g, light[1]),
+        b: channel(color.b, memory.b, light[2]),
+    }
+}
+
+/// Keep the FOV map in step with a changed tile, without rebuilding it
+fn update_fov_tile(x: i32, y: i32, map: &Map, tcod: &mut Tcod) {
+    let tile = &map[x as usize][y as usize];
+    tcod.fov.set(x, y, !tile.block_sight, !tile.blocked);
+    tcod.light_fov.set(x, y, !tile.block_sight, !tile.blocked);
+    tcod.fov_stale = true;
+}
+
+/// How hard the player's equipped digging tool bites into walls
+fn digging_power(game: &Game) -> Option<i32> {
+    game.inventory
+        .iter()
+        .filter(|item| item.item == Some(Item::Pick))
+        .filter_map(|item| item.equipment)
+        .find(|equipment| equipment.equipped)
+        .map(|equipment| equipment.damage)
+}
+
+/// Spend a turn digging at a wall, carrying on next turn until it is dug out
+fn dig(x: i32, y: i32, tcod: &mut Tcod, game: &mut Game, objects: &mut [Object]) {
+    let power = match digging_power(game) {
+        Some(power) => power,
+        None => {
+            game.messages.add("You have nothing to dig with.", WHITE);
+            return;
+        }
+    };
+    make_noise(x, y, DIG_NOISE_RADIUS, &game.map, objects);
+    if damage_wall(x, y, power, &mut game.map) {
+        break_wall(x, y, Tile::empty(), &mut game.map, tcod);
+        game.messages.add("You dig through the wall.", WHITE);
+    } else if is_diggable(x, y, &game.map) {
+        game.activity = Some(Activity::Dig { x, y });
+    }
+}
+
+/// A creature strong enough to break walls smashes at one standing between
+/// it and its target. Returns false if there is no such wall
+fn smash_towards(id: usize, target_x: i32, target_y: i32, tcod: &mut Tcod, game: &mut Game, objects: &mut [Object]) -> bool {
+    let smash = objects[id].fighter.map_or(0, |f| f.smash);
+    let (dx, dy) = step_towards(objects[id].pos(), (target_x, target_y));
+    let (x, y) = (objects[id].x + dx, objects[id].y + dy);
+    if smash == 0 || !is_diggable(x, y, &game.map) {
+        return false;
+    }
+
+    let seen = tcod.is_visible(objects[id].x, objects[id].y);
+    if seen {
+        game.messages.add(format!("The {} smashes at the wall!", objects[id].name), LIGHT_RED);
+    }
+    make_noise(x, y, FIGHT_NOISE_RADIUS, &game.map, objects);
+    if damage_wall(x, y, smash, &mut game.map) {
+        break_wall(x, y, Tile::rubble(), &mut game.map, tcod);
+        if seen {
+            game.messages.add("The wall crumbles!", LIGHT_RED);
+        }
+    }
+    true
+}
+
+/// Find the nearest free tile around a position, reachable from it
+fn free_spot_near(x: i32, y: i32, map: &Map, objects: &[Object]) -> Option<(i32, i32)> {
+    find_path(
+        (x, y),
+        map,
+        |nx, ny| !map[nx as usize][ny as usize].blocked,
+        |nx, ny| !is_blocked(nx, ny, map, objects) && !map[nx as usize][ny as usize].terrain.is_hazard(),
+    )
+    .and_then(|path| path.last().cloned())
+}
+
+/// Find the cheapest path from `start` to the nearest tile accepted by
+/// `is_goal`, moving in all eight directions through tiles accepted by
+/// `passable` and paying each tile's terrain move cost. The path doesn't
+/// include the start tile.
+fn find_path<P, G>(start: (i32, i32), map: &Map, passable: P, is_goal: G) -> Option<Vec<(i32, i32)>>
+where
+    P: Fn(i32, i32) -> bool,
+    G: Fn(i32, i32) -> bool,
+{
+    if is_goal(start.0, start.1) {
+        return Some(vec![]);
+    }
+
+    // remember the cost to reach each tile and where it was reached from,
+    // to walk the path back
+    let (width, height) = map_size(map);
+    let mut costs = vec![vec![None; height as usize]; width as usize];
+    let mut came_from = vec![vec![None; height as usize]; width as usize];
+    costs[start.0 as usize][start.1 as usize] = Some(0);
+
+    let mut frontier = BinaryHeap::new();
+    frontier.push(Reverse((0, start.0, start.1)));
+
+    while let Some(Reverse((cost, x, y))) = frontier.pop() {
+        if costs[x as usize][y as usize] != Some(cost) {
+            continue;
+        }
+        if (x, y) != start && is_goal(x, y) {
+            let mut path = vec![];
+            let mut current = (x, y);
+            while current != start {
+                path.push(current);
+                current = came_from[current.0 as usize][current.1 as usize].unwrap();
+            }
+            path.reverse();
+            return Some(path);
+        }
+        for dx in -1..=1 {
+            for dy in -1..=1 {
+                let (nx, ny) = (x + dx, y + dy);
+                if nx < 0 || ny < 0 || nx >= width || ny >= height {
+                    continue;
+                }
+                // goals are entered even when they can't be walked through
+                if !passable(nx, ny) && !is_goal(nx, ny) {
+                    continue;
+                }
+                let new_cost = cost + map[nx as usize][ny as usize].terrain.move_cost();
+                if costs[nx as usize][ny as usize].map_or(true, |old| new_cost < old) {
+                    costs[nx as usize][ny as usize] = Some(new_cost);
+                    came_from[nx as usize][ny as usize] = Some((x, y));
+                    frontier.push(Reverse((new_cost, nx, ny)));
+                }
+            }
+        }
+    }
+    None
+}
+
+/// Number of steps from `start` to every tile within `max_distance`
+/// steps, walking through unblocked tiles only. Tiles that can't be
+/// reached are None
+fn path_distances(start: (i32, i32), max_distance: i32, map: &Map) -> Vec<Vec<Option<i32>>> {
+    let (width, height) = map_size(map);
+    let mut distances = vec![vec![None; height as usize]; width as usize];
+    distances[start.0 as usize][start.1 as usize] = Some(0);
+
+    let mut frontier = VecDeque::new();
+    frontier.push_back((start, 0));
+
+    while let Some(((x, y), distance)) = frontier.pop_front() {
+        if distance >= max_distance {
+            continue;
+        }
+        for dx in -1..=1 {
+            for dy in -1..=1 {
+                let (nx, ny) = (x + dx, y + dy);
+                if !in_map(nx, ny, map) {
+                    continue;
+                }
+                if distances[nx as usize][ny as usize].is_some()
+                    || map[nx as usize][ny as usize].blocked
+                {
+                    continue;
+                }
+                distances[nx as usize][ny as usize] = Some(distance + 1);
+                frontier.push_back(((nx, ny), distance + 1));
+            }
+        }
+    }
+    distances
+}
+
+/// A "Dijkstra map away from" a position: every reachable tile gets a
+/// value that decreases the further it is from the position, after
+/// smoothing so that moving downhill leads towards open areas rather than
+/// into the nearest dead end
+fn flee_map(from: (i32, i32), map: &Map) -> Vec<Vec<Option<i32>>> {
+    // distances are scaled by ten to keep the coefficient in integers
+    let mut flee_map: Vec<Vec<Option<i32>>> = path_distances(from, i32::MAX, map)
+        .iter()
+        .map(|column| column.iter().map(|d| d.map(|d| d * -12)).collect())
+        .collect();
+
+    let (width, height) = map_size(map);
+    let mut frontier = BinaryHeap::new();
+    for x in 0..width {
+        for y in 0..height {
+            if let Some(value) = flee_map[x as usize][y as usize] {
+                frontier.push(Reverse((value, x, y)));
+            }
+        }
+    }
+
+    // rescan from the lowest tiles, so each tile ends up no more than one
+    // step above its lowest neighbour
+    while let Some(Reverse((value, x, y))) = frontier.pop() {
+        if flee_map[x as usize][y as usize] != Some(value) {
+            continue;
+        }
+        for dx in -1..=1 {
+            for dy in -1..=1 {
+                let (nx, ny) = (x + dx, y + dy);
+                if !in_map(nx, ny, map) {
+                    continue;
+                }
+                match flee_map[nx as usize][ny as usize] {
+                    Some(neighbour) if neighbour > value + 10 => {
+                        flee_map[nx as usize][ny as usize] = Some(value + 10);
+                        frontier.push(Reverse((value + 10, nx, ny)));
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+    flee_map
 }
 
 /// Advance to the next level
@@ -1207,11 +4678,86 @@ fn next_level(tcod: &mut Tcod, game: &mut Game, objects: &mut Vec<Object>) {
         RED,
     );
 
-    game.dungeon_level += 1;
-    game.map = make_map(objects, game.dungeon_level);
+    change_level(game.dungeon_level + 1, true, tcod, game, objects);
+}
+
+/// Climb back to the previous level, arriving on its stairs down. Leaving
+/// the dungeon with the crown wins the game
+fn previous_level(tcod: &mut Tcod, game: &mut Game, objects: &mut Vec<Object>) -> PlayerAction {
+    if game.dungeon_level == 1 {
+        if game.inventory.iter().any(|item| item.item == Some(Item::Artifact)) {
+            return PlayerAction::Victory;
+        }
+        game.messages.add(
+            "You can't leave without the Crown of the Ancient Kings!",
+            RED,
+        );
+        return PlayerAction::DidntTaketurn;
+    }
+
+    game.messages.add("You climb back towards the surface...", VIOLET);
+    change_level(game.dungeon_level - 1, true, tcod, game, objects);
+    PlayerAction::DidntTaketurn
+}
+
+/// Leave the current level for another one, taking followers along when
+/// using the stairs. A level the player has been to before comes back the
+/// way it was left
+fn change_level(depth: u32, by_stairs: bool, tcod: &mut Tcod, game: &mut Game, objects: &mut Vec<Object>) {
+    let going_up = depth < game.dungeon_level;
+    let (followers, left_behind) = if by_stairs {
+        take_followers(objects)
+    } else {
+        (vec![], objects.drain(1..).collect())
+    };
+    game.visited.push(Level {
+        depth: game.dungeon_level,
+        map: std::mem::take(&mut game.map),
+        objects: left_behind,
+    });
+    game.dungeon_level = depth;
+
+    let visited = game.visited.iter().position(|level| level.depth == depth);
+    if let Some(index) = visited {
+        let level = game.visited.swap_remove(index);
+        game.map = level.map;
+        objects.extend(level.objects);
+    } else {
+        game.map = make_map(objects, depth, level_seed(game.seed, depth));
+    }
+
+    // arrive on the stairs leading back to where we came from
+    if going_up || visited.is_some() {
+        let stairs_name = if going_up { "stairs" } else { "up stairs" };
+        if let Some(stairs) = objects.iter().find(|o| o.name == stairs_name) {
+            let (x, y) = stairs.pos();
+            objects[PLAYER].set_pos(x, y);
+        }
+    }
+    place_followers(followers, game, objects);
     initialize_fov(tcod, &game.map);
 }
 
+/// Split off the allies close enough to follow the player off the level
+/// from everything else on it
+fn take_followers(objects: &mut Vec<Object>) -> (Vec<Object>, Vec<Object>) {
+    let player = objects[PLAYER].clone();
+    objects.drain(1..).partition(|o| {
+        o.ai == Some(AI::Ally { order: Order::Follow }) && o.distance_to(&player) <= ALLY_FOLLOW_DISTANCE
+    })
+}
+
+/// Put followers down around the player on the new level
+fn place_followers(followers: Vec<Object>, game: &Game, objects: &mut Vec<Object>) {
+    let (x, y) = objects[PLAYER].pos();
+    for mut follower in followers {
+        if let Some((x, y)) = free_spot_near(x, y, &game.map, objects) {
+            follower.set_pos(x, y);
+            objects.push(follower);
+        }
+    }
+}
+
 struct Transition {
     level: u32,
     value: u32,
@@ -1227,20 +4773,453 @@ fn from_dungeon_level(table: &[Transition], level: u32) -> u32 {
         .map_or(0, |transition| transition.value)
 }
 
-fn place_objects(room: Rect, map: &Map, objects: &mut Vec<Object>, level: u32) {
-
-    let max_monsters = from_dungeon_level(
+/// Create a monster of the given kind at a position
+fn create_monster(kind: &str, x: i32, y: i32) -> Object {
+    match kind {
+        "broo" => Object {
+            x: x,
+            y: y,
+            glyph: 'b',
+            color: DESATURATED_CRIMSON,
+            name: "Broo".to_string(),
+            blocks: true,
+            alive: true,
+            fighter: Some(Fighter {
+                base_max_hp: 20,
+                hp: 20,
+                base_defense: 0,
+                base_power: 4,
+                xp: 35,
+                constitution: 5,
+                regen: 0,
+                perception: 6,
+                alertness: Alertness::Awake,
+                morale: 30,
+                burning: 0,
+                delay: 0,
+                smash: 0,
+                confused: 0,
+                on_death: DeathCallback::Monster,
+            }),
+            ai: Some(AI::Basic),
+            item: None,
+            always_visible: false,
+            level: 1,
+            equipment: None,
+            group: None,
+            faction: Faction::Chaos,
+            trap: None,
+            light: None,
+            quantity: 1,
+        },
+        "broo shaman" => Object {
+            x: x,
+            y: y,
+            glyph: 's',
+            color: DESATURATED_CRIMSON,
+            name: "Broo Shaman".to_string(),
+            blocks: true,
+            alive: true,
+            fighter: Some(Fighter {
+                base_max_hp: 20,
+                hp: 20,
+                base_defense: 0,
+                base_power: 4,
+                xp: 60,
+                constitution: 5,
+                regen: 0,
+                perception: 8,
+                alertness: Alertness::Awake,
+                morale: 40,
+                burning: 0,
+                delay: 0,
+                smash: 0,
+                confused: 0,
+                on_death: DeathCallback::Monster,
+            }),
+            ai: Some(AI::Caster {
+                spells: vec![Spell::HealAlly, Spell::Confuse, Spell::Summon, Spell::Bolt],
+                mana: CASTER_MAX_MANA,
+                cooldown: 0,
+            }),
+            item: None,
+            always_visible: false,
+            level: 3,
+            equipment: None,
+            group: None,
+            faction: Faction::Chaos,
+            trap: None,
+            // the shaman's magic gives off a faint blue glow
+            light: Some(Light { radius: MAGIC_LIGHT_RADIUS, intensity: MAGIC_LIGHT_INTENSITY, color: MAGIC_LIGHT }),
+            quantity: 1,
+        },
+        "broo archer" => Object {
+            x: x,
+            y: y,
+            glyph: 'a',
+            color: DESATURATED_CRIMSON,
+            name: "Broo Archer".to_string(),
+            blocks: true,
+            alive: true,
+            fighter: Some(Fighter {
+                base_max_hp: 15,
+                hp: 15,
+                base_defense: 0,
+                base_power: 3,
+                xp: 45,
+                constitution: 5,
+                regen: 0,
+                perception: 8,
+                alertness: Alertness::Awake,
+                morale: 30,
+                burning: 0,
+                delay: 0,
+                smash: 0,
+                confused: 0,
+                on_death: DeathCallback::Monster,
+            }),
+            ai: Some(AI::Ranged {
+                range: 4.0,
+                ammo: 8,
+                cooldown: 0,
+            }),
+            item: None,
+            always_visible: false,
+            level: 2,
+            equipment: None,
+            group: None,
+            faction: Faction::Chaos,
+            trap: None,
+            light: None,
+            quantity: 1,
+        },
+        "troll" => Object {
+            x: x,
+            y: y,
+            glyph: 'T',
+            color: DARK_GREEN,
+            name: "Troll".to_string(),
+            blocks: true,
+            alive: true,
+            fighter: Some(Fighter {
+                base_max_hp: 30,
+                hp: 30,
+                base_defense: 2,
+                base_power: 8,
+                xp: 100,
+                constitution: 15,
+                regen: 0,
+                perception: 5,
+                alertness: Alertness::Awake,
+                morale: 100,
+                burning: 0,
+                delay: 0,
+                smash: 25,
+                confused: 0,
+                on_death: DeathCallback::Monster,
+            }),
+            ai: Some(AI::Basic),
+            item: None,
+            always_visible: false,
+            level: 3,
+            equipment: None,
+            group: None,
+            faction: Faction::Trolls,
+            trap: None,
+            light: None,
+            quantity: 1,
+        },
+        "scorpion man" => Object {
+            x: x,
+            y: y,
+            glyph: 'S',
+            color: BRASS,
+            name: "Scorpion Man".to_string(),
+            blocks: true,
+            alive: true,
+            fighter: Some(Fighter {
+                base_max_hp: 40,
+                hp: 40,
+                base_defense: 2,
+                base_power: 10,
+                xp: 125,
+                constitution: 8,
+                regen: 0,
+                perception: 7,
+                alertness: Alertness::Awake,
+                morale: 70,
+                burning: 0,
+                delay: 0,
+                smash: 0,
+                confused: 0,
+                on_death: DeathCallback::Monster,
+            }),
+            ai: Some(AI::Basic),
+            item: None,
+            always_visible: false,
+            level: 4,
+            equipment: None,
+            group: None,
+            faction: Faction::Chaos,
+            trap: None,
+            light: None,
+            quantity: 1,
+        },
+        "sellsword" => Object {
+            x: x,
+            y: y,
+            glyph: '@',
+            color: LIGHT_SKY,
+            name: "Sellsword".to_string(),
+            blocks: true,
+            alive: true,
+            fighter: Some(Fighter {
+                base_max_hp: 30,
+                hp: 30,
+                base_defense: 1,
+                base_power: 5,
+                xp: 0,
+                constitution: 10,
+                regen: 0,
+                perception: 6,
+                alertness: Alertness::Alert,
+                morale: 100,
+                burning: 0,
+                delay: 0,
+                smash: 0,
+                confused: 0,
+                on_death: DeathCallback::Monster,
+            }),
+            // minds its own business until the player hires it
+            ai: Some(AI::Basic),
+            item: None,
+            always_visible: false,
+            level: 2,
+            equipment: None,
+            group: None,
+            faction: Faction::Sellswords,
+            trap: None,
+            light: None,
+            quantity: 1,
+        },
+        "spirit hound" => Object {
+            x: x,
+            y: y,
+            glyph: 'h',
+            color: LIGHT_SKY,
+            name: "Spirit Hound".to_string(),
+            blocks: true,
+            alive: true,
+            fighter: Some(Fighter {
+                base_max_hp: 20,
+                hp: 20,
+                base_defense: 0,
+                base_power: 5,
+                xp: 0,
+                constitution: 5,
+                regen: 0,
+                perception: 10,
+                alertness: Alertness::Alert,
+                morale: 100,
+                burning: 0,
+                delay: 0,
+                smash: 0,
+                confused: 0,
+                on_death: DeathCallback::Monster,
+            }),
+            ai: Some(AI::Ally { order: Order::Follow }),
+            item: None,
+            always_visible: false,
+            level: 1,
+            equipment: None,
+            group: None,
+            faction: Faction::Player,
+            trap: None,
+            light: None,
+            quantity: 1,
+        },
+        "skeleton" => Object {
+            x: x,
+            y: y,
+            glyph: 'z',
+            color: LIGHTEST_GREY,
+            name: "Skeleton".to_string(),
+            blocks: true,
+            alive: true,
+            fighter: Some(Fighter {
+                base_max_hp: 15,
+                hp: 15,
+                base_defense: 1,
+                base_power: 5,
+                xp: 20,
+                constitution: 0,
+                regen: 0,
+                perception: 6,
+                alertness: Alertness::Awake,
+                morale: 100,
+                burning: 0,
+                delay: 0,
+                smash: 0,
+                confused: 0,
+                on_death: DeathCallback::Monster,
+            }),
+            ai: Some(AI::Basic),
+            item: None,
+            always_visible: false,
+            level: 1,
+            equipment: None,
+            group: None,
+            faction: Faction::Chaos,
+            trap: None,
+            light: None,
+            quantity: 1,
+        },
+        "vrimak" => Object {
+            x: x,
+            y: y,
+            glyph: 'B',
+            color: CRIMSON,
+            name: "Broo Chieftain Vrimak".to_string(),
+            blocks: true,
+            alive: true,
+            fighter: Some(Fighter {
+                base_max_hp: 60,
+                hp: 60,
+                base_defense: 2,
+                base_power: 8,
+                xp: 300,
+                constitution: 15,
+                regen: 0,
+                perception: 8,
+                alertness: Alertness::Awake,
+                morale: 100,
+                burning: 0,
+                delay: 0,
+                smash: 0,
+                confused: 0,
+                on_death: DeathCallback::Monster,
+            }),
+            // calls his kin to his side and patches them up
+            ai: Some(AI::Boss {
+                spells: vec![Spell::Summon, Spell::HealAlly],
+                mana: CASTER_MAX_MANA,
+                cooldown: 0,
+            }),
+            item: None,
+            always_visible: false,
+            level: 4,
+            equipment: None,
+            group: None,
+            faction: Faction::Chaos,
+            trap: None,
+            light: None,
+            quantity: 1,
+        },
+        "scorpion queen" => Object {
+            x: x,
+            y: y,
+            glyph: 'Q',
+            color: GOLD,
+            name: "Scorpion Queen".to_string(),
+            blocks: true,
+            alive: true,
+            fighter: Some(Fighter {
+                base_max_hp: 90,
+                hp: 90,
+                base_defense: 4,
+                base_power: 12,
+                xp: 600,
+                // her wounds close almost as fast as they are dealt
+                constitution: 40,
+                regen: 0,
+                perception: 9,
+                alertness: Alertness::Awake,
+                morale: 100,
+                burning: 0,
+                delay: 0,
+                smash: 0,
+                confused: 0,
+                on_death: DeathCallback::Monster,
+            }),
+            ai: Some(AI::Boss {
+                spells: vec![Spell::Confuse, Spell::HealAlly],
+                mana: CASTER_MAX_MANA,
+                cooldown: 0,
+            }),
+            item: None,
+            always_visible: false,
+            level: 7,
+            equipment: None,
+            group: None,
+            faction: Faction::Chaos,
+            trap: None,
+            light: None,
+            quantity: 1,
+        },
+        "ancient king" => Object {
+            x: x,
+            y: y,
+            glyph: 'K',
+            color: LIGHT_PURPLE,
+            name: "Ancient King".to_string(),
+            blocks: true,
+            alive: true,
+            fighter: Some(Fighter {
+                base_max_hp: 150,
+                hp: 150,
+                base_defense: 5,
+                base_power: 14,
+                xp: 1000,
+                constitution: 20,
+                regen: 0,
+                perception: 10,
+                alertness: Alertness::Awake,
+                morale: 100,
+                burning: 0,
+                delay: 0,
+                smash: 0,
+                confused: 0,
+                on_death: DeathCallback::Monster,
+            }),
+            // raises his fallen guards as skeletons
+            ai: Some(AI::Boss {
+                spells: vec![Spell::RaiseDead, Spell::Bolt, Spell::Confuse],
+                mana: CASTER_MAX_MANA,
+                cooldown: 0,
+            }),
+            item: None,
+            always_visible: false,
+            level: 10,
+            equipment: None,
+            group: None,
+            faction: Faction::Chaos,
+            trap: None,
+            light: None,
+            quantity: 1,
+        },
+        _ => unreachable!(),
+    }
+}
+
+/// Chance for a broo to be an archer, also used for pack followers
+fn archer_chance(level: u32) -> u32 {
+    from_dungeon_level(
         &[
-            Transition { level: 1, value: 2 },
-            Transition { level: 4, value: 3 },
-            Transition { level: 6, value: 5 },
+            Transition {
+                level: 2,
+                value: 20,
+            },
+            Transition {
+                level: 4,
+                value: 35,
+            },
         ],
         level,
-    );
-
-
-    let num_monsters = rand::thread_rng().gen_range(0, max_monsters + 1);
+    )
+}
 
+/// Pick a random kind of monster for the level
+fn random_monster(level: u32, rng: &mut StdRng) -> &'static str {
     let troll_chance = from_dungeon_level( 
         &[
             Transition {
@@ -1278,6 +5257,18 @@ fn place_objects(room: Rect, map: &Map, objects: &mut Vec<Object>, level: u32) {
         );
     
 
+    let archer_chance = archer_chance(level);
+
+    let sellsword_chance = from_dungeon_level(
+        &[
+            Transition {
+                level: 2,
+                value: 5,
+            },
+        ],
+        level,
+    );
+
     let scorpion_chance = from_dungeon_level( 
         &[
             Transition {
@@ -1296,132 +5287,23 @@ fn place_objects(room: Rect, map: &Map, objects: &mut Vec<Object>, level: u32) {
         level,
         );
 
-        for _ in 0..num_monsters {
-            let x = rand::thread_rng().gen_range(room.x1 + 1, room.x2);
-            let y = rand::thread_rng().gen_range(room.y1 + 1, room.y2);
-
-            if !is_blocked(x, y, map, objects) {
-            // Randomly select monster
-            let monster_chances = [
-                ("broo", 80),
-                ("troll", troll_chance),
-                ("broo shaman", shaman_chance),
-                ("scorpion man", scorpion_chance),
-            ];
-
-            let dist = WeightedIndex::new(monster_chances.iter().map(
-                |item| item.1)).unwrap();    
-                
-            let mut rng = rand::thread_rng();
-
-            let choice = monster_chances[dist.sample(&mut rng)].0;
-
-            let mut monster = match choice {
-                "broo" => Object {
-                    x: x,
-                    y: y,
-                    glyph: 'b',
-                    color: DESATURATED_CRIMSON,
-                    name: "Broo".to_string(),
-                    blocks: true,
-                    alive: true,
-                    fighter: Some(Fighter {
-                        base_max_hp: 20,
-                        hp: 20,
-                        base_defense: 0,
-                        base_power: 4,
-                        xp: 35,
-                        on_death: DeathCallback::Monster,
-                    }),
-                    ai: Some(AI::Basic),
-                    item: None,
-                    always_visible: false,
-                    level: 1,
-                    equipment: None,
-                },
-                "broo shaman" => Object {
-                    x: x,
-                    y: y,
-                    glyph: 's',
-                    color: DESATURATED_CRIMSON,
-                    name: "Broo Shaman".to_string(),
-                    blocks: true,
-                    alive: true,
-                    fighter: Some(Fighter {
-                        base_max_hp: 20,
-                        hp: 20,
-                        base_defense: 0,
-                        base_power: 4,
-                        xp: 60,
-                        on_death: DeathCallback::Monster,
-                    }),
-                    ai: Some(AI::Ranged { range: 4.0 }),
-                    item: None,
-                    always_visible: false,
-                    level: 3,
-                    equipment: None,
-                },
-                "troll" => Object {
-                        x: x,
-                        y: y,
-                        glyph: 'T',
-                        color: DARK_GREEN,
-                        name: "Troll".to_string(),
-                        blocks: true,
-                        alive: true,
-                        fighter: Some(Fighter {
-                            base_max_hp: 30,
-                            hp: 30,
-                            base_defense: 2,
-                            base_power: 8,
-                            xp: 100,
-                            on_death: DeathCallback::Monster,
-                        }),
-                        ai: Some(AI::Basic),
-                        item: None,
-                        always_visible: false,
-                        level: 3,
-                        equipment: None,
-                    },
-                "scorpion man" => Object {
-                    x: x,
-                    y: y,
-                    glyph: 'S',
-                    color: BRASS,
-                    name: "Scorpion Man".to_string(),
-                    blocks: true,
-                    alive: true,
-                    fighter: Some(Fighter {
-                        base_max_hp: 40,
-                        hp: 40,
-                        base_defense: 2,
-                        base_power: 10,
-                        xp: 125,
-                        on_death: DeathCallback::Monster,
-                    }),
-                    ai: Some(AI::Basic),
-                    item: None,
-                    always_visible: false,
-                    level: 4,
-                    equipment: None,
-                },
-            _ => unreachable!(),
-            };
-    
-            objects.push(monster);
-        }
-    }
-
-    // Place Items
+    let monster_chances = [
+        ("broo", 80),
+        ("troll", troll_chance),
+        ("broo shaman", shaman_chance),
+        ("broo archer", archer_chance),
+        ("scorpion man", scorpion_chance),
+        ("sellsword", sellsword_chance),
+    ];
 
-    let max_items = from_dungeon_level(
-        &[
-            Transition { level: 1, value: 1 },
-            Transition { level: 4, value: 2 },
-        ],
-        level,
-    );
+    let dist = WeightedIndex::new(monster_chances.iter().map(
+        |item| item.1)).unwrap();    
+        
+    monster_chances[dist.sample(rng)].0
+}
 
+/// Pick a random kind of item for the level
+fn random_item(level: u32, rng: &mut StdRng) -> Item {
     // Item random table
     let item_chances = [
         (Item::Heal, 35),
@@ -1452,6 +5334,42 @@ fn place_objects(room: Rect, map: &Map, objects: &mut Vec<Object>, level: u32) {
             ],
             level,
         )),
+        (Item::Summon, from_dungeon_level(
+            &[
+                Transition {
+                    level: 2,
+                    value: 5,
+                }
+            ],
+            level,
+        )),
+        (Item::Charm, from_dungeon_level(
+            &[
+                Transition {
+                    level: 3,
+                    value: 5,
+                }
+            ],
+            level,
+        )),
+        (Item::Identify, from_dungeon_level(
+            &[
+                Transition {
+                    level: 2,
+                    value: 10,
+                }
+            ],
+            level,
+        )),
+        (Item::RemoveCurse, from_dungeon_level(
+            &[
+                Transition {
+                    level: 2,
+                    value: 5,
+                }
+            ],
+            level,
+        )),
         (Item::Sword, from_dungeon_level(
             &[
                 Transition {
@@ -1479,6 +5397,24 @@ fn place_objects(room: Rect, map: &Map, objects: &mut Vec<Object>, level: u32) {
             ],
             level,
         )),
+        (Item::Pick, from_dungeon_level(
+            &[
+                Transition {
+                    level: 1,
+                    value: 5,
+                }
+            ],
+            level,
+        )),
+        (Item::Torch, from_dungeon_level(
+            &[
+                Transition {
+                    level: 1,
+                    value: 15,
+                }
+            ],
+            level,
+        )),
         (Item::Bow, from_dungeon_level(
             &[
                 Transition {
@@ -1491,216 +5427,466 @@ fn place_objects(room: Rect, map: &Map, objects: &mut Vec<Object>, level: u32) {
     ];
     
     let item_dist = WeightedIndex::new(item_chances.iter().map(
-        |item| item.1)).unwrap();    
-      
-
-    let num_items = rand::thread_rng().gen_range(0, max_items + 1);
+        |item| item.1)).unwrap();
 
-    for _ in 0..num_items {
-        // choose random spot for this item
-        let x = rand::thread_rng().gen_range(room.x1 + 1, room.x2);
-        let y = rand::thread_rng().gen_range(room.y1 + 1, room.y2);
+    item_chances[item_dist.sample(rng)].0
+}
 
-        if !is_blocked(x, y, map, objects) {
-  
-            let mut rng = rand::thread_rng();
-
-            let choice = item_chances[item_dist.sample(&mut rng)].0;
-
-            let mut item = match choice {
-                Item::Heal => {
-                // create healing potion
-                let mut object = Object::new(x, y, '!', VIOLET, "healing potion".to_string(), false);
-                object.item = Some(Item::Heal);
-                object
-            }
-            Item::Lightning => {
-                // create a lightning bolt scroll
-                let mut object = Object::new(
-                    x,
-                    y, 
-                    '#',
-                    LIGHT_YELLOW,
-                    "scroll of lightning bolt".to_string(),
-                    false,
-                );
-                object.item = Some(Item::Lightning);
-                object
-            } 
-            Item::Confuse => {
-                // create confuse scroll (10% chance)
-                let mut object = Object::new(
-                    x,
-                    y,
-                    '?',
-                    LIGHT_AZURE,
-                    "scroll of confusion".to_string(),
-                    false,
-                );
-                object.item = Some(Item::Confuse);
-                object
-            } 
-            Item::Fireball => {
-                // create fireball scroll
-                let mut object = Object::new(
-                    x,
-                    y,
-                    'F',
-                    ORANGE,
-                    "scroll of fireball".to_string(),
-                    false,
-                );
-                object.item = Some(Item::Fireball);
-                object
-            }
-            Item::Sword => {
-                // create a sword
-                let mut object = Object::new(x, y, '/', SKY, "sword".to_string(), false);
-                object.item = Some(Item::Sword);
-
-                match level {
-                    1 | 2 => {
-                        object.name = "short sword".to_string();
-                        object.equipment = Some(Equipment{ equipped: false, slot: Slot::RightHand, power_bonus: 3, defense_bonus: 0, max_hp_bonus: 0, range: 0, damage: 0, charges: 0});
-                    }
-                    3 | 4 | 5 => {
-                        object.name = "broadsword".to_string();
-                        object.equipment = Some(Equipment{ equipped: false, slot: Slot::RightHand, power_bonus: 4, defense_bonus: 0, max_hp_bonus: 0, range: 0, damage: 0, charges: 0});
-                    }
-                    6 | 7 | 8 => {
-                        object.name = "fine sword".to_string();
-                        object.equipment = Some(Equipment{ equipped: false, slot: Slot::RightHand, power_bonus: 6, defense_bonus: 0, max_hp_bonus: 0, range: 0, damage: 0, charges: 0});
-                    }
-                    l if l > 8 => {
-                        object.name = "enchanted sword".to_string();
-                        object.equipment = Some(Equipment{ equipped: false, slot: Slot::RightHand, power_bonus: 8, defense_bonus: 0, max_hp_bonus: 0, range: 0, damage: 0, charges: 0});
-                    }
-                    _ => unreachable!()
+/// Create an item of the given kind, better ones the deeper it is found
+fn create_item(kind: Item, x: i32, y: i32, level: u32) -> Object {
+    let mut item = match kind {
+        Item::Heal => {
+            // create healing potion
+            let mut object = Object::new(x, y, '!', VIOLET, "healing potion".to_string(), false);
+            object.item = Some(Item::Heal);
+            object
+        }
+        Item::Lightning => {
+            // create a lightning bolt scroll
+            let mut object = Object::new(
+                x,
+                y, 
+                '?',
+                LIGHT_YELLOW,
+                "scroll of lightning bolt".to_string(),
+                false,
+            );
+            object.item = Some(Item::Lightning);
+            object
+        } 
+        Item::Confuse => {
+            // create confuse scroll (10% chance)
+            let mut object = Object::new(
+                x,
+                y,
+                '?',
+                LIGHT_YELLOW,
+                "scroll of confusion".to_string(),
+                false,
+            );
+            object.item = Some(Item::Confuse);
+            object
+        } 
+        Item::Summon => {
+            // create summoning scroll
+            let mut object = Object::new(
+                x,
+                y,
+                '?',
+                LIGHT_YELLOW,
+                "scroll of summoning".to_string(),
+                false,
+            );
+            object.item = Some(Item::Summon);
+            object
+        }
+        Item::Charm => {
+            // create charm scroll
+            let mut object = Object::new(
+                x,
+                y,
+                '?',
+                LIGHT_YELLOW,
+                "scroll of charming".to_string(),
+                false,
+            );
+            object.item = Some(Item::Charm);
+            object
+        }
+        Item::Fireball => {
+            // create fireball scroll
+            let mut object = Object::new(
+                x,
+                y,
+                '?',
+                LIGHT_YELLOW,
+                "scroll of fireball".to_string(),
+                false,
+            );
+            object.item = Some(Item::Fireball);
+            object
+        }
+        Item::Sword => {
+            // create a sword
+            let mut object = Object::new(x, y, '/', SKY, "sword".to_string(), false);
+            object.item = Some(Item::Sword);
+
+            match level {
+                1 | 2 => {
+                    object.name = "short sword".to_string();
+                    object.equipment = Some(Equipment{ equipped: false, slot: Slot::RightHand, power_bonus: 3, defense_bonus: 0, max_hp_bonus: 0, range: 0, damage: 0, charges: 0, weight: 2, enchantment: 0, affix: None, cursed: false, identified: false});
+                }
+                3 | 4 | 5 => {
+                    object.name = "broadsword".to_string();
+                    object.equipment = Some(Equipment{ equipped: false, slot: Slot::RightHand, power_bonus: 4, defense_bonus: 0, max_hp_bonus: 0, range: 0, damage: 0, charges: 0, weight: 3, enchantment: 0, affix: None, cursed: false, identified: false});
+                }
+                6 | 7 | 8 => {
+                    object.name = "fine sword".to_string();
+                    object.equipment = Some(Equipment{ equipped: false, slot: Slot::RightHand, power_bonus: 6, defense_bonus: 0, max_hp_bonus: 0, range: 0, damage: 0, charges: 0, weight: 3, enchantment: 0, affix: None, cursed: false, identified: false});
                 }
-                object
+                l if l > 8 => {
+                    object.name = "enchanted sword".to_string();
+                    object.equipment = Some(Equipment{ equipped: false, slot: Slot::RightHand, power_bonus: 8, defense_bonus: 0, max_hp_bonus: 0, range: 0, damage: 0, charges: 0, weight: 4, enchantment: 0, affix: None, cursed: false, identified: false});
+                }
+                _ => unreachable!()
             }
-            Item::Shield => {
-                // create a shield
-                let mut object = Object::new(x, y, ')', SKY, "shield".to_string(), false);
-                object.item = Some(Item::Shield);
-
-                match level {
-                    1 | 2 => {
-                        object.name = "wooden shield".to_string();
-                        object.equipment = Some(Equipment{ equipped: false, slot: Slot::LeftHand, power_bonus: 0, defense_bonus: 2, max_hp_bonus: 0, range: 0, damage: 0, charges: 0});
-                    }
-                    3 | 4 | 5 => {
-                        object.name = "round shield".to_string();
-                        object.equipment = Some(Equipment{ equipped: false, slot: Slot::LeftHand, power_bonus: 0, defense_bonus: 3, max_hp_bonus: 0, range: 0, damage: 0, charges: 0});
-                    }
-                    6 | 7 | 8 => {
-                        object.name = "kite shield".to_string();
-                        object.equipment = Some(Equipment{ equipped: false, slot: Slot::LeftHand, power_bonus: 0, defense_bonus: 4, max_hp_bonus: 0, range: 0, damage: 0, charges: 0});
-                    }
-                    l if l > 8 => {
-                        object.name = "enchanted shield".to_string();
-                        object.equipment = Some(Equipment{ equipped: false, slot: Slot::LeftHand, power_bonus: 0, defense_bonus: 6, max_hp_bonus: 0, range: 0, damage: 0, charges: 0});
-                    }
-                    _ => unreachable!()
+            object
+        }
+        Item::Shield => {
+            // create a shield
+            let mut object = Object::new(x, y, ')', SKY, "shield".to_string(), false);
+            object.item = Some(Item::Shield);
+
+            match level {
+                1 | 2 => {
+                    object.name = "wooden shield".to_string();
+                    object.equipment = Some(Equipment{ equipped: false, slot: Slot::LeftHand, power_bonus: 0, defense_bonus: 2, max_hp_bonus: 0, range: 0, damage: 0, charges: 0, weight: 3, enchantment: 0, affix: None, cursed: false, identified: false});
+                }
+                3 | 4 | 5 => {
+                    object.name = "round shield".to_string();
+                    object.equipment = Some(Equipment{ equipped: false, slot: Slot::LeftHand, power_bonus: 0, defense_bonus: 3, max_hp_bonus: 0, range: 0, damage: 0, charges: 0, weight: 4, enchantment: 0, affix: None, cursed: false, identified: false});
                 }
-                object
+                6 | 7 | 8 => {
+                    object.name = "kite shield".to_string();
+                    object.equipment = Some(Equipment{ equipped: false, slot: Slot::LeftHand, power_bonus: 0, defense_bonus: 4, max_hp_bonus: 0, range: 0, damage: 0, charges: 0, weight: 5, enchantment: 0, affix: None, cursed: false, identified: false});
+                }
+                l if l > 8 => {
+                    object.name = "enchanted shield".to_string();
+                    object.equipment = Some(Equipment{ equipped: false, slot: Slot::LeftHand, power_bonus: 0, defense_bonus: 6, max_hp_bonus: 0, range: 0, damage: 0, charges: 0, weight: 5, enchantment: 0, affix: None, cursed: false, identified: false});
+                }
+                _ => unreachable!()
             }
-            Item::Helmet => {
-                // create a helmet
-                let mut object = Object::new(x, y, 'M', SKY, "helmet".to_string(), false);
-                object.item = Some(Item::Helmet);
-
-                match level {
-                    1 | 2 => {
-                        object.name = "leather helmet".to_string();
-                        object.equipment = Some(Equipment{ equipped: false, slot: Slot::Head, power_bonus: 0, defense_bonus: 0, max_hp_bonus: 15, range: 0, damage: 0, charges: 0});
-                    }
-                    3 | 4 | 5 => {
-                        object.name = "pot helm".to_string();
-                        object.equipment = Some(Equipment{ equipped: false, slot: Slot::Head, power_bonus: 0, defense_bonus: 0, max_hp_bonus: 30, range: 0, damage: 0, charges: 0});
-                    }
-                    6 | 7 | 8 => {
-                        object.name = "full helm".to_string();
-                        object.equipment = Some(Equipment{ equipped: false, slot: Slot::Head, power_bonus: 0, defense_bonus: 0, max_hp_bonus: 45, range: 0, damage: 0, charges: 0});
-                    }
-                    l if l > 8 => {
-                        object.name = "enchanted helm".to_string();
-                        object.equipment = Some(Equipment{ equipped: false, slot: Slot::Head, power_bonus: 0, defense_bonus: 0, max_hp_bonus: 80, range: 0, damage: 0, charges: 0});
-                    }
-                    _ => unreachable!()
+            object
+        }
+        Item::Helmet => {
+            // create a helmet
+            let mut object = Object::new(x, y, 'M', SKY, "helmet".to_string(), false);
+            object.item = Some(Item::Helmet);
+
+            match level {
+                1 | 2 => {
+                    object.name = "leather helmet".to_string();
+                    object.equipment = Some(Equipment{ equipped: false, slot: Slot::Head, power_bonus: 0, defense_bonus: 0, max_hp_bonus: 15, range: 0, damage: 0, charges: 0, weight: 1, enchantment: 0, affix: None, cursed: false, identified: false});
+                }
+                3 | 4 | 5 => {
+                    object.name = "pot helm".to_string();
+                    object.equipment = Some(Equipment{ equipped: false, slot: Slot::Head, power_bonus: 0, defense_bonus: 0, max_hp_bonus: 30, range: 0, damage: 0, charges: 0, weight: 2, enchantment: 0, affix: None, cursed: false, identified: false});
+                }
+                6 | 7 | 8 => {
+                    object.name = "full helm".to_string();
+                    object.equipment = Some(Equipment{ equipped: false, slot: Slot::Head, power_bonus: 0, defense_bonus: 0, max_hp_bonus: 45, range: 0, damage: 0, charges: 0, weight: 3, enchantment: 0, affix: None, cursed: false, identified: false});
+                }
+                l if l > 8 => {
+                    object.name = "enchanted helm".to_string();
+                    object.equipment = Some(Equipment{ equipped: false, slot: Slot::Head, power_bonus: 0, defense_bonus: 0, max_hp_bonus: 80, range: 0, damage: 0, charges: 0, weight: 3, enchantment: 0, affix: None, cursed: false, identified: false});
+                }
+                _ => unreachable!()
+            }
+            object
+        }
+        Item::Bow => {
+            // create a helmet
+            let mut object = Object::new(x, y, '}', SKY, "bow".to_string(), false);
+            object.item = Some(Item::Bow);
+
+            match level {
+                1 | 2 => {
+                    object.name = "short bow".to_string();
+                    object.equipment = Some(Equipment{ equipped: false, slot: Slot::Back, power_bonus: 0, defense_bonus: 0, max_hp_bonus: 0, range: 4, damage: 5, charges: 12, weight: 1, enchantment: 0, affix: None, cursed: false, identified: false});
+                }
+                3 | 4 | 5 => {
+                    object.name = "longbow".to_string();
+                    object.equipment = Some(Equipment{ equipped: false, slot: Slot::Back, power_bonus: 0, defense_bonus: 0, max_hp_bonus: 0, range: 5, damage: 6, charges: 12, weight: 2, enchantment: 0, affix: None, cursed: false, identified: false});
+                }
+                6 | 7 | 8 => {
+                    object.name = "crossbow".to_string();
+                    object.equipment = Some(Equipment{ equipped: false, slot: Slot::Back, power_bonus: 0, defense_bonus: 0, max_hp_bonus: 0, range: 6, damage: 8, charges: 12, weight: 3, enchantment: 0, affix: None, cursed: false, identified: false});
                 }
-                object
+                l if l > 8 => {
+                    object.name = "magic bow".to_string();
+                    object.equipment = Some(Equipment{ equipped: false, slot: Slot::Back, power_bonus: 0, defense_bonus: 0, max_hp_bonus: 0, range: 8, damage: 10, charges: 12, weight: 2, enchantment: 0, affix: None, cursed: false, identified: false});
+                }
+                _ => unreachable!()
             }
-            Item::Bow => {
-                // create a helmet
-                let mut object = Object::new(x, y, '}', SKY, "bow".to_string(), false);
-                object.item = Some(Item::Bow);
+            object
+        }
+        Item::Pick => {
+            // create a digging tool, whose damage is what it does to walls
+            let mut object = Object::new(x, y, '(', SKY, "pick".to_string(), false);
+            object.item = Some(Item::Pick);
 
-                match level {
-                    1 | 2 => {
-                        object.name = "short bow".to_string();
-                        object.equipment = Some(Equipment{ equipped: false, slot: Slot::Back, power_bonus: 0, defense_bonus: 0, max_hp_bonus: 0, range: 4, damage: 5, charges: 12});
-                    }
-                    3 | 4 | 5 => {
-                        object.name = "longbow".to_string();
-                        object.equipment = Some(Equipment{ equipped: false, slot: Slot::Back, power_bonus: 0, defense_bonus: 0, max_hp_bonus: 0, range: 5, damage: 6, charges: 12});
-                    }
-                    6 | 7 | 8 => {
-                        object.name = "crossbow".to_string();
-                        object.equipment = Some(Equipment{ equipped: false, slot: Slot::Back, power_bonus: 0, defense_bonus: 0, max_hp_bonus: 0, range: 6, damage: 8, charges: 12});
+            if level < 5 {
+                object.equipment = Some(Equipment{ equipped: false, slot: Slot::RightHand, power_bonus: 1, defense_bonus: 0, max_hp_bonus: 0, range: 0, damage: 20, charges: 0, weight: 3, enchantment: 0, affix: None, cursed: false, identified: false});
+            } else {
+                object.name = "mattock".to_string();
+                object.equipment = Some(Equipment{ equipped: false, slot: Slot::RightHand, power_bonus: 2, defense_bonus: 0, max_hp_bonus: 0, range: 0, damage: 34, charges: 0, weight: 5, enchantment: 0, affix: None, cursed: false, identified: false});
+            }
+            object
+        }
+        Item::Identify => {
+            let mut object = Object::new(x, y, '?', LIGHT_YELLOW, "scroll of identify".to_string(), false);
+            object.item = Some(Item::Identify);
+            object
+        }
+        Item::RemoveCurse => {
+            let mut object = Object::new(x, y, '?', LIGHT_YELLOW, "scroll of remove curse".to_string(), false);
+            object.item = Some(Item::RemoveCurse);
+            object
+        }
+        Item::Torch => create_torch(x, y),
+        // only found on the final level
+        Item::Artifact => create_artifact(x, y),
+    };
+    item.always_visible = true;
+    item
+}
+
+/// Maybe enchant, curse or give an affix to a piece of equipment, naming it
+/// to match. Torches are never magical
+fn add_magic(item: &mut Object, level: u32, rng: &mut StdRng) {
+    if item.item == Some(Item::Torch) {
+        return;
+    }
+    let equipment = match item.equipment.as_mut() {
+        Some(equipment) => equipment,
+        None => return,
+    };
+    let depth_bonus = MAGIC_CHANCE_PER_LEVEL * level as i32;
+
+    if rng.gen_range(0, 100) < CURSE_CHANCE {
+        equipment.cursed = true;
+        equipment.enchantment = -rng.gen_range(1, MAX_ENCHANTMENT + 1);
+    } else if rng.gen_range(0, 100) < ENCHANT_BASE_CHANCE + depth_bonus {
+        let max = (1 + level as i32 / 3).min(MAX_ENCHANTMENT);
+        equipment.enchantment = rng.gen_range(1, max + 1);
+    }
+    if !equipment.cursed && rng.gen_range(0, 100) < AFFIX_BASE_CHANCE + depth_bonus {
+        let affixes = [Affix::Speed, Affix::FireResistance, Affix::Vampiric];
+        equipment.affix = affixes.choose(rng).cloned();
+    }
+
+    // the enchantment goes into whatever the item is good for, and only
+    // shows in the name once the player knows about it
+    let enchantment = equipment.enchantment;
+    equipment.boost(enchantment);
+
+    if let Some(affix) = equipment.affix {
+        item.name = format!("{} {}", item.name, affix);
+    }
+}
+
+fn place_objects(room: Rect, map: &Map, objects: &mut Vec<Object>, level: u32, rng: &mut StdRng) {
+
+    let max_monsters = from_dungeon_level(
+        &[
+            Transition { level: 1, value: 2 },
+            Transition { level: 4, value: 3 },
+            Transition { level: 6, value: 5 },
+        ],
+        level,
+    );
+
+
+    let num_monsters = rng.gen_range(0, max_monsters + 1);
+
+    let archer_chance = archer_chance(level);
+
+        for _ in 0..num_monsters {
+            let x = rng.gen_range(room.x1 + 1, room.x2);
+            let y = rng.gen_range(room.y1 + 1, room.y2);
+
+            if !is_blocked(x, y, map, objects) {
+            let choice = random_monster(level, rng);
+            let mut monster = create_monster(choice, x, y);
+
+            // many monsters are caught napping
+            let asleep = rng.gen::<f32>() < SLEEPING_MONSTER_CHANCE && !is_ally(&monster);
+            if asleep {
+                monster.fighter.as_mut().unwrap().alertness = Alertness::Asleep;
+            }
+
+            if choice == "broo" && rng.gen::<f32>() < PACK_CHANCE {
+                // the broo leads a pack of its kin, which sleeps when it does
+                let id = objects.len() as u32;
+                monster.group = Some(Group { id, leader: true });
+                objects.push(monster);
+
+                let num_followers = rng.gen_range(1, PACK_MAX_FOLLOWERS + 1);
+                for _ in 0..num_followers {
+                    let x = rng.gen_range(room.x1 + 1, room.x2);
+                    let y = rng.gen_range(room.y1 + 1, room.y2);
+                    if is_blocked(x, y, map, objects) {
+                        continue;
                     }
-                    l if l > 8 => {
-                        object.name = "magic bow".to_string();
-                        object.equipment = Some(Equipment{ equipped: false, slot: Slot::Back, power_bonus: 0, defense_bonus: 0, max_hp_bonus: 0, range: 8, damage: 10, charges: 12});
+                    let kind = if rng.gen_range(0, 100) < archer_chance {
+                        "broo archer"
+                    } else {
+                        "broo"
+                    };
+                    let mut follower = create_monster(kind, x, y);
+                    follower.group = Some(Group { id, leader: false });
+                    if asleep {
+                        follower.fighter.as_mut().unwrap().alertness = Alertness::Asleep;
                     }
-                    _ => unreachable!()
+                    objects.push(follower);
                 }
-                object
+            } else {
+                objects.push(monster);
             }
-        };
-        item.always_visible = true;
-        objects.push(item);
         }
     }
+
+    // Place traps, more of them deeper down
+
+    let trap_chance = from_dungeon_level(
+        &[
+            Transition { level: 1, value: 10 },
+            Transition { level: 3, value: 20 },
+            Transition { level: 6, value: 30 },
+        ],
+        level,
+    );
+
+    if rng.gen_range(0, 100) < trap_chance {
+        let x = rng.gen_range(room.x1 + 1, room.x2);
+        let y = rng.gen_range(room.y1 + 1, room.y2);
+        if !is_blocked(x, y, map, objects) && trap_at(x, y, objects).is_none() {
+            let trap = create_trap(random_trap(level, rng), x, y);
+            objects.push(trap);
+        }
+    }
+
+    // Light the room: sconces on the walls grow rarer deeper down, while
+    // glowing fungi only grow in the lower levels
+
+    let sconce_chance = from_dungeon_level(
+        &[
+            Transition { level: 1, value: 70 },
+            Transition { level: 3, value: 40 },
+            Transition { level: 6, value: 20 },
+        ],
+        level,
+    );
+
+    if rng.gen_range(0, 100) < sconce_chance {
+        // against the top wall of the room
+        let x = rng.gen_range(room.x1 + 1, room.x2);
+        let y = room.y1 + 1;
+        if !is_blocked(x, y, map, objects) && map[x as usize][(y - 1) as usize].terrain == Terrain::Wall {
+            let mut sconce = Object::new(x, y, '|', YELLOW, "wall sconce".to_string(), false);
+            sconce.always_visible = true;
+            sconce.light = Some(Light { radius: SCONCE_RADIUS, intensity: 1.0, color: TORCH_LIGHT });
+            objects.push(sconce);
+        }
+    }
+
+    let fungus_chance = from_dungeon_level(&[Transition { level: 3, value: 40 }], level);
+
+    if rng.gen_range(0, 100) < fungus_chance {
+        let x = rng.gen_range(room.x1 + 1, room.x2);
+        let y = rng.gen_range(room.y1 + 1, room.y2);
+        if !is_blocked(x, y, map, objects) {
+            let mut fungus = Object::new(x, y, ',', LIGHT_GREEN, "glowing fungus".to_string(), false);
+            fungus.light = Some(Light { radius: FUNGUS_RADIUS, intensity: FUNGUS_INTENSITY, color: FUNGUS_LIGHT });
+            objects.push(fungus);
+        }
+    }
+
+    // Place Items
+
+    let max_items = from_dungeon_level(
+        &[
+            Transition { level: 1, value: 1 },
+            Transition { level: 4, value: 2 },
+        ],
+        level,
+    );
+
+    let num_items = rng.gen_range(0, max_items + 1);
+
+    for _ in 0..num_items {
+        // choose random spot for this item
+        let x = rng.gen_range(room.x1 + 1, room.x2);
+        let y = rng.gen_range(room.y1 + 1, room.y2);
+
+        if !is_blocked(x, y, map, objects) {
+            let mut item = create_item(random_item(level, rng), x, y, level);
+            add_magic(&mut item, level, rng);
+            objects.push(item);
+        }
+    }
+}
+
+/// Top left corner of the view, keeping the player centered but never
+/// scrolling past the map edges. Maps smaller than the view are centered
+fn camera_position(player: (i32, i32), map: &Map) -> (i32, i32) {
+    let (width, height) = map_size(map);
+    let axis = |pos: i32, map_len: i32, view_len: i32| {
+        if map_len <= view_len {
+            -(view_len - map_len) / 2
+        } else {
+            (pos - view_len / 2).max(0).min(map_len - view_len)
+        }
+    };
+    (axis(player.0, width, VIEW_WIDTH), axis(player.1, height, VIEW_HEIGHT))
+}
+
+/// Map position shown at a console cell, if any
+fn screen_to_map(screen: (i32, i32), camera: (i32, i32), map: &Map) -> Option<(i32, i32)> {
+    let (x, y) = (screen.0 + camera.0, screen.1 + camera.1);
+    let on_screen = screen.0 >= 0 && screen.1 >= 0 && screen.0 < VIEW_WIDTH && screen.1 < VIEW_HEIGHT;
+    if on_screen && in_map(x, y, map) {
+        Some((x, y))
+    } else {
+        None
+    }
+}
+
+/// Console cell showing a map position, if it is in view
+fn map_to_screen(pos: (i32, i32), camera: (i32, i32)) -> Option<(i32, i32)> {
+    let (x, y) = (pos.0 - camera.0, pos.1 - camera.1);
+    if x >= 0 && y >= 0 && x < VIEW_WIDTH && y < VIEW_HEIGHT {
+        Some((x, y))
+    } else {
+        None
+    }
+}
+
+/// Map position under the mouse cursor
+fn mouse_to_map(tcod: &Tcod, map: &Map) -> Option<(i32, i32)> {
+    screen_to_map((tcod.mouse.cx as i32, tcod.mouse.cy as i32), tcod.camera, map)
 }
 
 fn render_all(tcod: &mut Tcod, game: &mut Game, objects: &[Object], fov_recompute: bool) {
     // draw all objects in the list
 
     if fov_recompute {
-        // recompute FOV
-        let player = &objects[0];
-        tcod.fov
-            .compute_fov(player.x, player.y, TORCH_RADIUS, FOV_LIGHT_WALLS, FOV_ALGO);
+        // recompute FOV and lighting
+        compute_lighting(tcod, game, objects);
     }
 
-    let mut to_draw: Vec<_> = objects
-        .iter()
-        .filter(|o| {
-            tcod.fov.is_in_fov(o.x, o.y)
-            || (o.always_visible && game.map[o.x as usize][o.y as usize].explored) 
-        })
-        .collect();
-    // sort so that non-blocking objects come first
-    to_draw.sort_by(|o1, o2| { o1.blocks.cmp(&o2.blocks) });
-    // draw the objects in the list
-    for object in &to_draw {
-        object.draw(&mut tcod.con);
-    }
-
-    // Go through all tiles and set their background color
-    for y in 0..MAP_HEIGHT {
-        for x in 0..MAP_WIDTH {
-            let visible = tcod.fov.is_in_fov(x, y);
-            let wall = game.map[x as usize][y as usize].block_sight;
-            let color = match (visible, wall) {
-                // outside of FOV
-                (false, true) => COLOR_DARK_WALL,
-                (false, false) => COLOR_DARK_GROUND,
-                // inside FOV
-                (true, true) => COLOR_LIGHT_WALL,
-                (true, false) => COLOR_LIGHT_GROUND,
+    tcod.camera = camera_position(objects[PLAYER].pos(), &game.map);
+
+    // Go through all tiles in view and set their background color
+    for screen_y in 0..VIEW_HEIGHT {
+        for screen_x in 0..VIEW_WIDTH {
+            let (x, y) = match screen_to_map((screen_x, screen_y), tcod.camera, &game.map) {
+                Some(pos) => pos,
+                None => continue,
+            };
+            let visible = tcod.is_visible(x, y);
+            // visible tiles are shaded by the light on them, the rest are
+            // drawn as remembered
+            let shade = |color: Color| {
+                if visible {
+                    lit(color, tcod.light[x as usize][y as usize])
+                } else {
+                    remembered(color)
+                }
             };
+            let tile = &game.map[x as usize][y as usize];
+            let color = shade(tile.background());
+            let glyph = tile.glyph().map(|(glyph, glyph_color)| (glyph, shade(glyph_color)));
 
             let explored = &mut game.map[x as usize][y as usize].explored;
             if visible {
@@ -1710,11 +5896,30 @@ fn render_all(tcod: &mut Tcod, game: &mut Game, objects: &[Object], fov_recomput
             if *explored {
                 // show explored tiels only (any visible tile is explored already)
                 tcod.con
-                    .set_char_background(x, y, color, BackgroundFlag::Set);
+                    .set_char_background(screen_x, screen_y, color, BackgroundFlag::Set);
+                if let Some((glyph, glyph_color)) = glyph {
+                    tcod.con.set_default_foreground(glyph_color);
+                    tcod.con.put_char(screen_x, screen_y, glyph, BackgroundFlag::None);
+                }
             }
         }    
     }
 
+    // draw objects over the tiles
+    let mut to_draw: Vec<_> = objects
+        .iter()
+        .filter(|o| {
+            (tcod.is_visible(o.x, o.y) && !is_hidden_trap(o))
+            || (o.always_visible && game.map[o.x as usize][o.y as usize].explored) 
+        })
+        .collect();
+    // sort so that non-blocking objects come first
+    to_draw.sort_by(|o1, o2| { o1.blocks.cmp(&o2.blocks) });
+    // draw the objects in the list
+    for object in &to_draw {
+        object.draw(&mut tcod.con, tcod.camera);
+    }
+
     // prepare to render panel
     tcod.panel.set_default_background(BLACK);
     tcod.panel.clear();
@@ -1750,7 +5955,7 @@ fn render_all(tcod: &mut Tcod, game: &mut Game, objects: &[Object], fov_recomput
         0,
         BackgroundFlag::None,
         TextAlignment::Left,
-        get_names_under_mouse(tcod.mouse, objects, &tcod.fov),
+        get_names_under_mouse(tcod, game, objects),
     );
 
     // print game messages, one line at a time
@@ -1778,7 +5983,7 @@ fn render_all(tcod: &mut Tcod, game: &mut Game, objects: &[Object], fov_recomput
     blit(
         &tcod.con,
         (0, 0),
-        (MAP_WIDTH, MAP_HEIGHT),
+        (VIEW_WIDTH, VIEW_HEIGHT),
         &mut tcod.root,
         (0, 0),
         1.0,
@@ -1791,6 +5996,10 @@ fn handle_keys(tcod: &mut Tcod, game: &mut Game, objects: &mut Vec<Object>) -> P
 
     let player_alive = objects[PLAYER].alive;
 
+    if tcod.mouse.lbutton_pressed && player_alive {
+        return click_to_travel(tcod, game, objects);
+    }
+
     match (tcod.key, tcod.key.text(), player_alive) {
         // Alt+Enter: toggle fullscreen
         (
@@ -1816,6 +6025,26 @@ fn handle_keys(tcod: &mut Tcod, game: &mut Game, objects: &mut Vec<Object>) -> P
             TookTurn
         },
 
+        (Key { code: Text, ..}, "r", true) => {
+            // rest until healed or interrupted
+            let monster_id = closest_visible_monster(tcod, game, objects);
+            let player = &objects[PLAYER];
+            if let Some(monster_id) = monster_id {
+                game.messages.add(
+                    format!("You cannot rest with the {} in sight!", objects[monster_id].name),
+                    RED,
+                );
+                DidntTaketurn
+            } else if player.fighter.map_or(0, |f| f.hp) >= player.max_hp(game) {
+                game.messages.add("You are already at full health.", YELLOW);
+                DidntTaketurn
+            } else {
+                game.messages.add(format!("{} rests.", player.name), BLUE);
+                game.activity = Some(Activity::Rest);
+                TookTurn
+            }
+        },
+
         // movement keys
         (Key { code: Up, ..}, _, true) => {
             player_move_or_attack(0, -1, game, objects, tcod);
@@ -1838,6 +6067,7 @@ fn handle_keys(tcod: &mut Tcod, game: &mut Game, objects: &mut Vec<Object>) -> P
             // show the inventory
             let inventory_index = inventory_menu(
                 &game.inventory,
+                &game.identification,
                 "Press the key next to an item to use it, or any other key to cancel.\n",
                 &mut tcod.root,
             );
@@ -1851,15 +6081,32 @@ fn handle_keys(tcod: &mut Tcod, game: &mut Game, objects: &mut Vec<Object>) -> P
             // show the inventory; if an item is selected, drop it
             let inventory_index = inventory_menu(
                 &game.inventory,
+                &game.identification,
                 "Press the key next to an item to drop it, or any other key to cancel.\n",
                 &mut tcod.root,
             );
             if let Some(inventory_index) = inventory_index {
-                drop_item(inventory_index, game, objects);
+                drop_item(inventory_index, tcod, game, objects);
             }
             DidntTaketurn
         },
 
+        (Key { code: Text, ..}, "o", true) => {
+            // explore the level until something interesting turns up
+            let known_items = objects
+                .iter()
+                .filter(|o| o.item.is_some() && tcod.is_visible(o.x, o.y))
+                .map(|o| o.pos())
+                .collect();
+            game.activity = Some(Activity::Explore { known_items });
+            DidntTaketurn
+        },
+
+        (Key { code: Text, .. }, "O", _) => {
+            options_menu(tcod);
+            DidntTaketurn
+        },
+
         (Key { code: Text, .. }, "c", true) => {
             // show character information
             let player = &objects[PLAYER];
@@ -1874,15 +6121,38 @@ Experience: {}
 Experience to level up: {}
 
 Maximum HP: {}
+Constitution: {}
 Attack: {}
-Defense: {}",
-                    level, fighter.xp, level_up_xp, player.max_hp(game), player.power(game), player.defense(game)
+Defense: {}
+
+Seed: {}",
+                    level, fighter.xp, level_up_xp, player.max_hp(game), fighter.constitution, player.power(game), player.defense(game),
+                    game.seed
                 );
                 msgbox(&msg, CHARACTER_SCREEN_WIDTH, &mut tcod.root);
             }
             DidntTaketurn
         }
 
+        (Key { code: Text, ..}, "t", true) => {
+            // tell allies in sight what to do
+            command_allies(tcod, game, objects)
+        }
+
+        (Key { code: Text, ..}, "s", true) => {
+            // search the surroundings for traps
+            game.messages.add("You search for traps.", WHITE);
+            if !look_for_traps(TRAP_SPOT_RADIUS, SEARCH_SPOT_FACTOR, tcod, game, objects) {
+                game.messages.add("You find nothing.", WHITE);
+            }
+            TookTurn
+        }
+
+        (Key { code: Text, ..}, "x", true) => {
+            // disarm a trap next to the player
+            disarm_trap(tcod, game, objects)
+        }
+
         (Key { code: Text, ..}, "g", true) => {
             // pick up an item
             let item_id = objects
@@ -1904,12 +6174,312 @@ Defense: {}",
             }
             DidntTaketurn
         }
+
+        (Key { code: Text, ..}, ">", true) => {
+            // go up stairs if the player is on them
+            let player_on_stairs = objects
+                .iter()
+                .any(|object| object.pos() == objects[PLAYER].pos() && object.name == "up stairs");
+            if player_on_stairs {
+                previous_level(tcod, game, objects)
+            } else {
+                DidntTaketurn
+            }
+        }
         _ => DidntTaketurn
     }
 }
 
+/// Pick allies in sight and give them an order
+fn command_allies(tcod: &mut Tcod, game: &mut Game, objects: &mut [Object]) -> PlayerAction {
+    let allies: Vec<usize> = objects
+        .iter()
+        .enumerate()
+        .filter(|&(id, o)| id != PLAYER && is_ally(o) && tcod.is_visible(o.x, o.y))
+        .map(|(id, _)| id)
+        .collect();
+    if allies.is_empty() {
+        game.messages.add("You have no allies in sight.", WHITE);
+        return PlayerAction::DidntTaketurn;
+    }
+
+    let mut options = vec!["All allies".to_string()];
+    options.extend(allies.iter().map(|&id| objects[id].name.clone()));
+    let chosen = match menu("Command which ally?\n", &options, INVENTORY_WIDTH, &mut tcod.root) {
+        Some(0) => allies,
+        Some(choice) if choice <= allies.len() => vec![allies[choice - 1]],
+        _ => return PlayerAction::DidntTaketurn,
+    };
+
+    let orders = ["Follow me", "Stay here", "Attack"];
+    let order = match menu("What should they do?\n", &orders, INVENTORY_WIDTH, &mut tcod.root) {
+        Some(0) => Order::Follow,
+        Some(1) => Order::Stay,
+        Some(2) => {
+            game.messages.add(
+                "Left-click a monster to attack, or right-click to cancel.",
+                LIGHT_CYAN,
+            );
+            match target_monster(tcod, game, objects, None) {
+                Some(target_id) => {
+                    // setting allies on a neutral creature counts as attacking it
+                    objects[PLAYER].provoke(&objects[target_id], game);
+                    let (x, y) = objects[target_id].pos();
+                    Order::Attack { x, y }
+                }
+                None => return PlayerAction::DidntTaketurn,
+            }
+        }
+        _ => return PlayerAction::DidntTaketurn,
+    };
+
+    for id in chosen {
+        // a confused ally can't take orders until it comes to its senses
+        if let Some(AI::Ally { order: current }) = objects[id].ai.as_mut() {
+            *current = order;
+        }
+    }
+    game.messages.add("Your allies obey.", WHITE);
+    PlayerAction::TookTurn
+}
+
+/// Take the next step of the player's current activity, stopping it when
+/// it is finished or a key is pressed or a monster comes into view
+fn continue_activity(tcod: &mut Tcod, game: &mut Game, objects: &mut Vec<Object>) -> PlayerAction {
+    use PlayerAction::*;
+
+    let activity = match game.activity.take() {
+        Some(activity) => activity,
+        None => return DidntTaketurn,
+    };
+
+    if tcod.key.code != NoKey || tcod.mouse.lbutton_pressed || !objects[PLAYER].alive {
+        game.messages.add("You stop.", WHITE);
+        return DidntTaketurn;
+    }
+
+    // anything hostile in view is reason to stop, however far off
+    if let Some(monster_id) = closest_visible_monster(tcod, game, objects) {
+        game.messages.add(format!("You see the {}!", objects[monster_id].name), RED);
+        return DidntTaketurn;
+    }
+
+    match activity {
+        Activity::Rest => {
+            let player = &objects[PLAYER];
+            if player.fighter.map_or(0, |f| f.hp) >= player.max_hp(game) {
+                game.messages.add("You feel rested.", BLUE);
+                DidntTaketurn
+            } else {
+                game.activity = Some(Activity::Rest);
+                TookTurn
+            }
+        }
+        Activity::Explore { known_items } => explore_step(tcod, game, objects, known_items),
+        Activity::Travel { x, y } => travel_step(tcod, game, objects, x, y),
+        Activity::Dig { x, y } => {
+            dig(x, y, tcod, game, objects);
+            TookTurn
+        }
+    }
+}
+
+/// Walk to a left-clicked explored tile, or attack an adjacent monster
+fn click_to_travel(tcod: &mut Tcod, game: &mut Game, objects: &mut [Object]) -> PlayerAction {
+    use PlayerAction::*;
+
+    let (x, y) = match mouse_to_map(tcod, &game.map) {
+        Some(pos) => pos,
+        None => return DidntTaketurn,
+    };
+    if !game.map[x as usize][y as usize].explored {
+        return DidntTaketurn;
+    }
+
+    let (player_x, player_y) = objects[PLAYER].pos();
+    let (dx, dy) = (x - player_x, y - player_y);
+    if (dx, dy) == (0, 0) {
+        return DidntTaketurn;
+    }
+
+    let monster_there = objects
+        .iter()
+        .any(|o| o.fighter.is_some() && o.pos() == (x, y) && tcod.is_visible(x, y));
+    if monster_there && dx.abs() <= 1 && dy.abs() <= 1 {
+        player_move_or_attack(dx, dy, game, objects, tcod);
+        return TookTurn;
+    }
+
+    // walls, closed doors and hazards are no place to travel to
+    if game.map[x as usize][y as usize].blocked
+        || is_hazard(x, y, Faction::Player, &game.map, objects)
+        || travel_path((player_x, player_y), (x, y), &game.map, objects).is_none()
+    {
+        game.messages.add("You don't know a way there.", WHITE);
+        return DidntTaketurn;
+    }
+
+    game.activity = Some(Activity::Travel { x, y });
+    DidntTaketurn
+}
+
+/// Shortest path over explored, safe ground to a travel destination
+fn travel_path(
+    from: (i32, i32),
+    to: (i32, i32),
+    map: &Map,
+    objects: &[Object],
+) -> Option<Vec<(i32, i32)>> {
+    find_path(
+        from,
+        map,
+        |x, y| {
+            map[x as usize][y as usize].explored
+                && !is_blocked(x, y, map, objects)
+                && !is_hazard(x, y, Faction::Player, map, objects)
+        },
+        |x, y| (x, y) == to,
+    )
+}
+
+/// Take one step along the shortest known path to the travel destination
+fn travel_step(
+    tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut [Object],
+    x: i32,
+    y: i32,
+) -> PlayerAction {
+    use PlayerAction::*;
+
+    let player_pos = objects[PLAYER].pos();
+    if player_pos == (x, y) {
+        return DidntTaketurn;
+    }
+
+    match travel_path(player_pos, (x, y), &game.map, objects) {
+        Some(path) => {
+            let (next_x, next_y) = path[0];
+            let attacking = objects.iter().any(|o| o.fighter.is_some() && o.pos() == (next_x, next_y));
+            player_move_or_attack(next_x - player_pos.0, next_y - player_pos.1, game, objects, tcod);
+            if !attacking && objects[PLAYER].pos() == player_pos {
+                // something stopped us, so don't keep walking into it
+                return DidntTaketurn;
+            }
+            game.activity = Some(Activity::Travel { x, y });
+            TookTurn
+        }
+        None => {
+            game.messages.add("You don't know a way there.", WHITE);
+            DidntTaketurn
+        }
+    }
+}
+
+/// Take one step towards the nearest unexplored tile, picking up or
+/// stopping for newly spotted items, and finally head for the stairs
+fn explore_step(
+    tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut Vec<Object>,
+    known_items: Vec<(i32, i32)>,
+) -> PlayerAction {
+    use PlayerAction::*;
+
+    let player_pos = objects[PLAYER].pos();
+
+    let new_items: Vec<(i32, i32)> = objects
+        .iter()
+        .filter(|o| o.item.is_some() && tcod.is_visible(o.x, o.y))
+        .map(|o| o.pos())
+        .filter(|pos| !known_items.contains(pos))
+        .collect();
+
+    // a full inventory still takes items that join a stack
+    let can_carry = |item: &Object| {
+        game.inventory.len() < 26 || game.inventory.iter().any(|carried| carried.stacks_with(item))
+    };
+    let pickup = tcod.options.auto_explore_pickup
+        && objects
+            .iter()
+            .filter(|o| o.item.is_some() && new_items.contains(&o.pos()))
+            .all(can_carry);
+
+    if !new_items.is_empty() && !pickup {
+        // stop and let the player decide what to do with it
+        let item_id = objects
+            .iter()
+            .position(|o| o.item.is_some() && o.pos() == new_items[0])
+            .unwrap();
+        let name = game.identification.describe(&objects[item_id]);
+        game.messages.add(format!("You spot {}.", name), LIGHT_GREEN);
+        return DidntTaketurn;
+    }
+
+    if pickup && new_items.contains(&player_pos) {
+        let item_id = objects
+            .iter()
+            .position(|o| o.item.is_some() && o.pos() == player_pos)
+            .unwrap();
+        pick_item_up(item_id, game, objects);
+        game.activity = Some(Activity::Explore { known_items });
+        return TookTurn;
+    }
+
+    let map = &game.map;
+    let passable = |x: i32, y: i32| {
+        map[x as usize][y as usize].explored
+            && !is_blocked(x, y, map, objects)
+            && !is_hazard(x, y, Faction::Player, map, objects)
+    };
+
+    let path = find_path(player_pos, map, passable, |x, y| new_items.contains(&(x, y)))
+        .or_else(|| {
+            find_path(player_pos, map, passable, |x, y| {
+                let tile = &map[x as usize][y as usize];
+                !tile.explored && !tile.blocked && !tile.terrain.is_hazard()
+            })
+        })
+        .or_else(|| {
+            // everything is explored, so head for the stairs
+            let stairs = objects
+                .iter()
+                .find(|o| o.name == "stairs")
+                .map(|o| o.pos());
+            find_path(player_pos, map, passable, |x, y| Some((x, y)) == stairs)
+        });
+
+    match path {
+        Some(path) if !path.is_empty() => {
+            let (x, y) = path[0];
+            player_move_or_attack(x - player_pos.0, y - player_pos.1, game, objects, tcod);
+            game.activity = Some(Activity::Explore { known_items });
+            TookTurn
+        }
+        Some(_) => {
+            game.messages.add("You have explored this level and reached the stairs.", WHITE);
+            DidntTaketurn
+        }
+        None => {
+            game.messages.add("There is nothing left to explore.", WHITE);
+            DidntTaketurn
+        }
+    }
+}
+
 // Inventory
 fn menu<T: AsRef<str>>(header: &str, options: &[T], width: i32, root: &mut Root) -> Option<usize> {
+    let options: Vec<(&str, Color)> = options.iter().map(|option| (option.as_ref(), WHITE)).collect();
+    colored_menu(header, &options, width, root)
+}
+
+/// A menu whose options are each printed in their own color
+fn colored_menu<T: AsRef<str>>(
+    header: &str,
+    options: &[(T, Color)],
+    width: i32,
+    root: &mut Root,
+) -> Option<usize> {
 
     assert!(
         options.len() <= 26,
@@ -1938,9 +6508,10 @@ fn menu<T: AsRef<str>>(header: &str, options: &[T], width: i32, root: &mut Root)
     );
 
     // print all the options
-    for (index, option_text) in options.iter().enumerate() {
+    for (index, (option_text, color)) in options.iter().enumerate() {
         let menu_letter = (b'a' + index as u8) as char;
         let text = format!("({}) {}", menu_letter, option_text.as_ref());
+        window.set_default_foreground(*color);
         window.print_ex(
             0,
             header_height + index as i32,
@@ -1973,37 +6544,52 @@ fn menu<T: AsRef<str>>(header: &str, options: &[T], width: i32, root: &mut Root)
     }
 }
 
-fn inventory_menu(inventory: &[Object], header: &str, root: &mut Root) -> Option<usize> {
-    // how a menu with each item as an option
-    let options = if inventory.len() == 0 {
-        vec!["Inventory is empty".into()]
+fn inventory_menu(
+    inventory: &[Object],
+    identification: &Identification,
+    header: &str,
+    root: &mut Root,
+) -> Option<usize> {
+    // how a menu with each item as an option, colored by rarity
+    let options: Vec<(String, Color)> = if inventory.len() == 0 {
+        vec![("Inventory is empty".into(), WHITE)]
     } else {
         inventory
             .iter()
             .map(|item| {
                 // show additional information in case it's equippped
                 if item.equipment.is_some() {
-                    let equip = item.equipment.unwrap();
+                    // bonuses show without any enchantment the player hasn't found out
+                    let equip = item.equipment.unwrap().as_known();
+                    let item_name = identification.name_of(item);
                     let name = match equip {
-                        e if e.power_bonus > 0 => format!("{} +{}pow", item.name, e.power_bonus),
-                        e if e.defense_bonus > 0 => format!("{} +{}def", item.name, e.defense_bonus),
-                        e if e.max_hp_bonus > 0 => format!("{} +{}hp", item.name, e.max_hp_bonus),
-                        e if e.charges > 0 => format!("{} {} dam, {} range, {} charges", item.name, e.damage, e.range, e.charges),
-                        _ => format!("{}", item.name)
+                        e if e.power_bonus > 0 => format!("{} +{}pow", item_name, e.power_bonus),
+                        e if e.defense_bonus > 0 => format!("{} +{}def", item_name, e.defense_bonus),
+                        e if e.max_hp_bonus > 0 => format!("{} +{}hp", item_name, e.max_hp_bonus),
+                        e if item.item == Some(Item::Torch) => format!("{} ({} turns of fuel)", item_name, e.charges),
+                        e if e.charges > 0 => format!("{} {} dam, {} range, {} charges", item_name, e.damage, e.range, e.charges),
+                        _ => item_name,
                     };
-                    if equip.equipped {
-                        format!("{} (on {})", name, equip.slot)
-                    } else {
-                        format!("{}", name)
-                    }
+                    let name = match equip {
+                        // a curse only shows once the item is stuck on
+                        e if e.equipped && e.cursed => format!("{} (on {}, cursed)", name, e.slot),
+                        e if e.equipped => format!("{} (on {})", name, e.slot),
+                        _ => name,
+                    };
+                    (name, equip.rarity().color())
+                } else if item.quantity > 1 {
+                    // stacks show how many there are
+                    let name = plural(&identification.name_of(item));
+                    (format!("{} {}", item.quantity, name), WHITE)
                 } else {
-                    item.name.clone()
+                    // potions and scrolls go by their looks until identified
+                    (identification.name_of(item), WHITE)
                 }
             })
             .collect()
     };
 
-    let inventory_index = menu(header, &options, INVENTORY_WIDTH, root);
+    let inventory_index = colored_menu(header, &options, INVENTORY_WIDTH, root);
 
     // if an item was chosen, return it
     if inventory.len() > 0 {
@@ -2033,14 +6619,14 @@ fn target_tile(
         }
         render_all(tcod, game, objects, false);
 
-        let (x, y) = (tcod.mouse.cx as i32, tcod.mouse.cy as i32);
-
         // accept the target if the player clicked in FOV, and in case a range
         // is specified, if it's in that range
-        let in_fov = (x < MAP_WIDTH) && (y < MAP_HEIGHT) && tcod.fov.is_in_fov(x, y);
-        let in_range = max_range.map_or(true, |range| objects[PLAYER].distance(x, y) <= range);
-        if tcod.mouse.lbutton_pressed && in_fov && in_range {
-            return Some((x, y))
+        if let Some((x, y)) = mouse_to_map(tcod, &game.map) {
+            let in_fov = tcod.is_visible(x, y);
+            let in_range = max_range.map_or(true, |range| objects[PLAYER].distance(x, y) <= range);
+            if tcod.mouse.lbutton_pressed && in_fov && in_range {
+                return Some((x, y))
+            }
         }
         if tcod.mouse.rbutton_pressed || tcod.key.code == Escape {
             return None; // cancel if player right-clicked or hit escape
@@ -2071,14 +6657,26 @@ fn target_monster(
 }
 
 // return a string with the names of all objects under the mouse
-fn get_names_under_mouse(mouse: Mouse, objects: &[Object], fov_map: &FovMap) -> String {
-    let (x, y) = (mouse.cx as i32, mouse.cy as i32);
+fn get_names_under_mouse(tcod: &Tcod, game: &Game, objects: &[Object]) -> String {
+    let (x, y) = match mouse_to_map(tcod, &game.map) {
+        Some(pos) => pos,
+        None => return String::new(),
+    };
 
     // create a list of names of all objects at the mouse's coordinates in FOV
     let names = objects
         .iter()
-        .filter(|obj| obj.pos() == (x, y) && fov_map.is_in_fov(obj.x, obj.y))
-        .map(|obj| obj.name.clone())
+        .enumerate()
+        .filter(|&(_, obj)| {
+            obj.pos() == (x, y) && tcod.is_visible(obj.x, obj.y) && !is_hidden_trap(obj)
+        })
+        .map(|(id, obj)| match obj.fighter {
+            Some(fighter) if fighter.alertness == Alertness::Asleep => {
+                format!("{} (asleep)", obj.name)
+            }
+            Some(_) if id != PLAYER && is_ally(obj) => format!("{} (ally)", obj.name),
+            _ => game.identification.name_of(obj),
+        })
         .collect::<Vec<_>>();
 
     names.join(", ") // join the names
@@ -2132,6 +6730,15 @@ fn new_game(tcod: &mut Tcod) -> (Game, Vec<Object>) {
          base_defense: 1,
          base_power: 2,
          xp:  0,
+         constitution: 10,
+         regen: 0,
+         perception: 5,
+         alertness: Alertness::Alert,
+         morale: 100,
+         burning: 0,
+         delay: 0,
+         smash: 0,
+         confused: 0,
          on_death: DeathCallback::Player,
      });
     
@@ -2139,11 +6746,19 @@ fn new_game(tcod: &mut Tcod) -> (Game, Vec<Object>) {
      let mut objects = vec![player];
 
     // generate map
+    let seed = rand::random();
     let mut game = Game {
-        map: make_map(&mut objects, 1),
+        map: make_map(&mut objects, 1, level_seed(seed, 1)),
         messages: Messages::new(),
         inventory: vec![],
         dungeon_level: 1,
+        activity: None,
+        recent_deaths: vec![],
+        factions: FactionTable::default(),
+        identification: Identification::new(&mut rand::thread_rng()),
+        fallen: vec![],
+        visited: vec![],
+        seed,
     };
 
     // initial equipment: a dagger
@@ -2158,9 +6773,19 @@ fn new_game(tcod: &mut Tcod) -> (Game, Vec<Object>) {
         range: 0,
         damage: 0,
         charges: 0,
+        weight: 1,
+        enchantment: 0,
+        affix: None,
+        cursed: false,
+        identified: true,
     });
     game.inventory.push(dagger);
 
+    // and a torch to see by
+    let mut torch = create_torch(0, 0);
+    torch.equipment.as_mut().unwrap().equipped = true;
+    game.inventory.push(torch);
+
     initialize_fov(tcod, &game.map);
 
     // Welcome message
@@ -2168,6 +6793,13 @@ fn new_game(tcod: &mut Tcod) -> (Game, Vec<Object>) {
         "Welcome stranger! Prepare to perish in the Tombs of the Ancient Kings!",
         RED,
     );
+    game.messages.add(
+        format!(
+            "The Crown of the Ancient Kings lies {} levels down. Bring it back to the surface!",
+            FINAL_LEVEL
+        ),
+        GOLD,
+    );
 
     (game, objects)
 
@@ -2175,14 +6807,12 @@ fn new_game(tcod: &mut Tcod) -> (Game, Vec<Object>) {
 
 fn initialize_fov(tcod: &mut Tcod, map: &Map) {
     // populate the FOV map, accorging to generated map
-    for y in 0..MAP_HEIGHT {
-        for x in 0..MAP_WIDTH {
-            tcod.fov.set(
-                x,
-                y,
-                !map[x as usize][y as usize].block_sight,
-                !map[x as usize][y as usize].blocked,
-            );
+    let (width, height) = map_size(map);
+    tcod.fov = FovMap::new(width, height);
+    tcod.light_fov = FovMap::new(width, height);
+    for y in 0..height {
+        for x in 0..width {
+            update_fov_tile(x, y, map, tcod);
         }
     }
     // unexplored areas start black
@@ -2198,14 +6828,23 @@ fn play_game(tcod: &mut Tcod, game: &mut Game, objects: &mut Vec<Object>) {
         // clear contents of previous screen
         tcod.con.clear();
 
+        // a mouse click only counts for the frame it happened in
+        tcod.mouse.lbutton_pressed = false;
+        tcod.mouse.rbutton_pressed = false;
+
         match input::check_for_event(input::MOUSE | input::KEY_PRESS) {
-            Some((_, Event::Mouse(m))) => tcod.mouse = m,
+            Some((_, Event::Mouse(m))) => {
+                tcod.mouse = m;
+                // don't replay the last key press on mouse movement
+                tcod.key = Default::default();
+            }
             Some((_, Event::Key(k))) => tcod.key = k,
             _ => tcod.key = Default::default(),
         }
 
         // render the screen
-        let fov_recompute = previous_player_position != (objects[PLAYER].pos());
+        let fov_recompute = previous_player_position != (objects[PLAYER].pos())
+            || std::mem::take(&mut tcod.fov_stale);
         render_all(tcod, game, &objects, fov_recompute);
 
         tcod.root.flush();
@@ -2215,22 +6854,93 @@ fn play_game(tcod: &mut Tcod, game: &mut Game, objects: &mut Vec<Object>) {
 
         // handle keys and exit game if needed
         previous_player_position = objects[PLAYER].pos();
-        let player_action = handle_keys(tcod, game, objects);
+        let wading = objects[PLAYER].alive && objects[PLAYER].fighter.map_or(0, |f| f.delay) > 0;
+        let player_action = if wading {
+            // still making its way through slow terrain, so the turn passes
+            objects[PLAYER].fighter.as_mut().unwrap().delay -= 1;
+            PlayerAction::TookTurn
+        } else if game.activity.is_some() {
+            continue_activity(tcod, game, objects)
+        } else {
+            handle_keys(tcod, game, objects)
+        };
         if player_action == PlayerAction::Exit {
             save_game(game, objects).unwrap();
             break;
         }
+        if player_action == PlayerAction::Victory {
+            victory(tcod);
+            break;
+        }
 
         if objects[PLAYER].alive && player_action != PlayerAction::DidntTaketurn {
+            let hp_before = objects[PLAYER].fighter.map_or(0, |f| f.hp);
+
+            let moved = objects[PLAYER].pos() != previous_player_position;
+            apply_terrain(PLAYER, moved, tcod, game, objects);
+
+            // keep an eye out for traps, and stop to deal with any that turn up
+            if look_for_traps(TRAP_SPOT_RADIUS, TRAP_SPOT_FACTOR, tcod, game, objects) {
+                game.activity = None;
+            }
+
+            check_morale_after_deaths(game, objects);
             for id in 0..objects.len() {
-                if objects[id].ai.is_some() {
+                if id != PLAYER && objects[id].ai.is_some() {
                     ai_take_turn(id, tcod, game, objects);
                 }
             }
+            check_morale_after_deaths(game, objects);
+
+            // monsters that fell into chasms are gone from this level
+            let mut fallen = std::mem::take(&mut game.fallen);
+            fallen.sort_unstable();
+            for id in fallen.into_iter().rev() {
+                objects.remove(id);
+            }
+
+            // everything still alive slowly heals
+            for object in objects.iter_mut() {
+                if object.alive {
+                    object.regenerate(game);
+                }
+            }
+
+            // confusion wears off with time, whatever the player does
+            if let Some(fighter) = objects[PLAYER].fighter.as_mut() {
+                if fighter.confused > 0 {
+                    fighter.confused -= 1;
+                    if fighter.confused == 0 {
+                        game.messages.add("Your head clears.", LIGHT_GREEN);
+                    }
+                }
+            }
+
+            // the torch burns down, and lights move around with their owners
+            burn_torch(game);
+            tcod.fov_stale = true;
+
+            // getting hurt interrupts whatever the player was doing
+            if game.activity.is_some() && objects[PLAYER].fighter.map_or(0, |f| f.hp) < hp_before {
+                game.activity = None;
+                game.messages.add("You are hurt and stop what you are doing!", RED);
+            }
         }
     }
 }
 
+/// Show the victory screen. A won game is over, so its save goes too
+fn victory(tcod: &mut Tcod) {
+    let msg = format!(
+        "You step out into the daylight, the Crown of the Ancient Kings in your hands.\n\n\
+        The Tombs have given up their treasure, and your name will be sung for \
+        generations.\n\nYou reached depth {} and returned alive.\n\nPress any key.",
+        FINAL_LEVEL
+    );
+    msgbox(&msg, CHARACTER_SCREEN_WIDTH + 20, &mut tcod.root);
+    let _ = std::fs::remove_file("savegame");
+}
+
 /// msgbox
 fn msgbox(text: &str, width: i32, root: &mut Root) {
     let options: &[&str] = &[];
@@ -2271,7 +6981,7 @@ fn main_menu(tcod: &mut Tcod) {
         title_sink.append(title);
 
         // show options and wait for player's choice
-        let choices = &["Play a new game", "Continue game", "Quit"];
+        let choices = &["Play a new game", "Continue game", "Options", "Quit"];
         let choice = menu("", choices, 24, &mut tcod.root);
 
         match choice {
@@ -2295,7 +7005,8 @@ fn main_menu(tcod: &mut Tcod) {
                     }
                 }
             }
-            Some(2) => {
+            Some(2) => options_menu(tcod),
+            Some(3) => {
                 // quit
                 break;
             }
@@ -2304,6 +7015,59 @@ fn main_menu(tcod: &mut Tcod) {
     }
 }
 
+/// Options from the config file, or the defaults if there isn't one
+fn load_options() -> Options {
+    let mut json = String::new();
+    File::open(CONFIG_FILE)
+        .and_then(|mut file| file.read_to_string(&mut json))
+        .ok()
+        .and_then(|_| serde_json::from_str(&json).ok())
+        .unwrap_or_default()
+}
+
+fn save_options(options: &Options) -> Result<(), Box<dyn Error>> {
+    let json = serde_json::to_string_pretty(options)?;
+    let mut file = File::create(CONFIG_FILE)?;
+    file.write_all(json.as_bytes())?;
+    Ok(())
+}
+
+fn yes_no(value: bool) -> &'static str {
+    if value {
+        "yes"
+    } else {
+        "no"
+    }
+}
+
+/// Let the player change the options, saving them as they go
+fn options_menu(tcod: &mut Tcod) {
+    loop {
+        let options = tcod.options;
+        let choices = [
+            format!("Field of view: {}", options.fov),
+            format!("Light walls: {}", yes_no(options.light_walls)),
+            format!("Pick up items while exploring: {}", yes_no(options.auto_explore_pickup)),
+        ];
+        match menu("Options (Esc to go back)\n", &choices, OPTIONS_WIDTH, &mut tcod.root) {
+            Some(0) => {
+                let names: Vec<String> = FovSetting::ALL.iter().map(|s| s.to_string()).collect();
+                if let Some(choice) = menu("Field of view algorithm\n", &names, OPTIONS_WIDTH, &mut tcod.root) {
+                    tcod.options.fov = FovSetting::ALL[choice];
+                }
+            }
+            Some(1) => tcod.options.light_walls = !options.light_walls,
+            Some(2) => tcod.options.auto_explore_pickup = !options.auto_explore_pickup,
+            _ => break,
+        }
+        if save_options(&tcod.options).is_err() {
+            msgbox("\nCouldn't save the options.\n", OPTIONS_WIDTH, &mut tcod.root);
+        }
+    }
+    // whatever the player saw has to be worked out again
+    tcod.fov_stale = true;
+}
+
 fn save_game(game: &Game, objects: &[Object]) -> Result<(), Box<dyn Error>> {
     let save_data = serde_json::to_string(&(game, objects))?;
     let mut file = File::create("savegame")?;
@@ -2315,12 +7079,90 @@ fn load_game() -> Result<(Game, Vec<Object>), Box<dyn Error>> {
     let mut json_save_state = String::new();
     let mut file = File::open("savegame")?;
     file.read_to_string(&mut json_save_state)?;
-    let result = serde_json::from_str::<(Game, Vec<Object>)>(&json_save_state)?;
+    let mut save: serde_json::Value = serde_json::from_str(&json_save_state)?;
+    // saves from before sellswords: new factions start out as they would in a new game
+    if let Some(relations) = save.pointer_mut("/0/factions/relations").and_then(|r| r.as_array_mut()) {
+        let fresh = FactionTable::default().relations;
+        for (row, fresh_row) in relations.iter_mut().zip(&fresh) {
+            if let Some(row) = row.as_array_mut() {
+                for relation in &fresh_row[row.len()..] {
+                    row.push(serde_json::to_value(relation)?);
+                }
+            }
+        }
+        for row in &fresh[relations.len()..] {
+            relations.push(serde_json::to_value(row)?);
+        }
+    }
+    // saves from before factions: everything takes the side of its kind
+    for path in &["/0/inventory", "/1"] {
+        let objects = save.pointer_mut(path).and_then(|objects| objects.as_array_mut());
+        for object in objects.into_iter().flatten().filter(|object| object.get("faction").is_none()) {
+            let faction = serde_json::to_value(faction_of_kind(object))?;
+            object["faction"] = faction;
+        }
+    }
+    let result = serde_json::from_value::<(Game, Vec<Object>)>(save)?;
     Ok(result)
 }
 
+/// The faction of an object saved without one. Trolls keep to themselves
+/// and other monsters are creatures of chaos, while the player and items
+/// are on the player's side like any new object
+fn faction_of_kind(object: &serde_json::Value) -> Faction {
+    match object["name"].as_str() {
+        _ if object["ai"].is_null() => Faction::Player,
+        Some("Troll") => Faction::Trolls,
+        _ => Faction::Chaos,
+    }
+}
+
+/// Generate every level for a range of seeds without opening a window,
+/// reporting maps the generator had to repair and failing on any that
+/// stay broken
+fn check_maps(num_seeds: u64) -> bool {
+    let mut repaired = 0;
+    let mut ok = true;
+    for seed in 0..num_seeds {
+        for level in 1..=FINAL_LEVEL {
+            let mut objects = vec![Object::new(0, 0, '@', WHITE, "player".to_string(), true)];
+
+            // the raw generator output, before any repairs
+            let map = generate_map(&mut objects, level, &mut StdRng::seed_from_u64(level_seed(seed, level)));
+            let unreachable = unreachable_positions(&map, &objects);
+            if !unreachable.is_empty() {
+                println!("seed {} level {}: unreachable {:?}", seed, level, unreachable);
+                repaired += 1;
+            }
+
+            let map = make_map(&mut objects, level, level_seed(seed, level));
+            if !unreachable_positions(&map, &objects).is_empty() {
+                println!("seed {} level {}: still broken after repair", seed, level);
+                ok = false;
+            }
+        }
+    }
+    println!(
+        "checked {} maps, {} needed repair",
+        num_seeds * FINAL_LEVEL as u64,
+        repaired
+    );
+    ok
+}
+
 fn main() {
 
+    // `--check-maps N` tests the map generator for N seeds and exits
+    let args: Vec<String> = std::env::args().collect();
+    if let Some(index) = args.iter().position(|arg| arg == "--check-maps") {
+        let num_seeds = args
+            .get(index + 1)
+            .and_then(|n| n.parse().ok())
+            .unwrap_or(1000);
+        let ok = check_maps(num_seeds);
+        std::process::exit(if ok { 0 } else { 1 });
+    }
+
     tcod::system::set_fps(LIMIT_FPS);
 
     let root = Root::initializer()
@@ -2336,14 +7178,30 @@ fn main() {
 
     let mut tcod = Tcod {
         root,
-        con: Offscreen::new(MAP_WIDTH, MAP_HEIGHT),
+        con: Offscreen::new(VIEW_WIDTH, VIEW_HEIGHT),
         panel: Offscreen::new(SCREEN_WIDTH, PANEL_HEIGHT),
-        fov: FovMap::new(MAP_WIDTH, MAP_HEIGHT),
+        fov: FovMap::new(VIEW_WIDTH, VIEW_HEIGHT),
         key: Default::default(),
         mouse: Default::default(),
         sink: sink,
+        camera: (0, 0),
+        fov_stale: false,
+        light_fov: FovMap::new(VIEW_WIDTH, VIEW_HEIGHT),
+        light: vec![],
+        visible: vec![],
+        options: load_options(),
      };
 
      main_menu(&mut tcod);
 
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generated_maps_are_connected() {
+        assert!(check_maps(20));
+    }
+}
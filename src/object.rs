@@ -1,216 +0,0 @@
-use tcod::colors::*;
-use tcod::console::*;
-
-use serde::{Serialize, Deserialize};
-
-pub const PLAYER: usize = 0;
-
-#[derive(Debug, Serialize, Deserialize)]
-struct Object {
-    x: i32,
-    y: i32,
-    glyph: char,
-    color: Color,
-    name: String,
-    blocks: bool,
-    alive: bool,
-    fighter: Option<Fighter>,
-    ai: Option<AI>,
-    item: Option<Item>,
-    always_visible: bool,
-    level: i32,
-    equipment: Option<Equipment>,
-}
-
-impl Object {
-    pub fn new(x: i32, y: i32, glyph: char, color: Color, name: String, blocks: bool) -> Self {
-        Object {
-            x: x,
-            y: y,
-            glyph: glyph,
-            color: color,
-            name: name,
-            blocks: blocks,
-            alive: false,
-            fighter: None,
-            ai: None,
-            item: None,
-            always_visible: false,
-            level: 1,
-            equipment: None,
-        }
-    }
-
-    pub fn draw(&self, con: &mut dyn Console) {
-        con.set_default_foreground(self.color);
-        con.put_char(self.x, self.y, self.glyph, BackgroundFlag::None);
-    }
-
-    pub fn pos(&self) -> (i32, i32) {
-        (self.x, self.y)
-    }
-
-    pub fn set_pos(&mut self, x: i32, y: i32) {
-        self.x = x;
-        self.y = y;
-    }
-
-    /// return the distance to another object
-    pub fn distance_to(&self, other: &Object) -> f32 {
-        let dx = other.x - self.x;
-        let dy = other.y - self.y;
-        ((dx.pow(2) + dy.pow(2)) as f32).sqrt()
-    }
-
-    /// return distance to a position
-    pub fn distance(&self, x: i32, y: i32) -> f32 {
-        (((x - self.x).pow(2) + (y - self.y).pow(2)) as f32).sqrt()
-    }
-
-    // Combat
-    pub fn take_damage(&mut self, damage: i32, game: &mut Game) -> Option<i32> {
-        // apply damage if possible
-        if let Some(fighter) = self.fighter.as_mut() {
-            if damage > 0 {
-                fighter.hp -= damage;
-            }
-        }
-
-        // check for death, call the death function
-        if let Some(fighter) = self.fighter {
-            if fighter.hp <= 0 {
-                self.alive = false;
-                fighter.on_death.callback(self, game);
-                return Some(fighter.xp);
-            }
-        }
-        None
-    }
-
-    pub fn heal(&mut self, amount: i32, game: &Game) {
-        // heal damage if possible
-        let max_hp = self.max_hp(game);
-
-        if let Some(ref mut fighter) = self.fighter {
-            fighter.hp += amount;
-            if fighter.hp > max_hp {
-                fighter.hp = max_hp;
-            }
-        }
-    }
-
-    pub fn attack(&mut self, target: &mut Object, game: &mut Game) {
-        // a simple formula for attack damage
-        let damage = self.power(game) - target.defense(game);
-        if damage > 0 {
-            // make the target take damage
-            game.messages.add(
-                format!(
-                "{} attacks {} for {} hit points.",
-                self.name, target.name, damage
-            ), ORANGE);
-            if let Some(xp) = target.take_damage(damage, game) {
-                // yield experience to player if target killed
-                self.fighter.as_mut().unwrap().xp += xp;
-            };
-        } else {
-            game.messages.add(format!(
-                "{} attacks {}, but it has no affect!",
-                self.name, target.name
-            ), GREEN);
-        }
-    }
-
-    /// Equip object and show a message about it
-    pub fn equip(&mut self, messages: &mut Messages) {
-        if self.item.is_none() {
-            messages.add(
-                format!("Can't equip {:?} because it's not an Item.", self),
-                RED,
-            );
-            return;
-        };
-        if let Some(ref mut equipment) = self.equipment {
-            if !equipment.equipped {
-                equipment.equipped = true;
-                messages.add(
-                    format!("Equipped {} on {:?}.", self.name, equipment.slot),
-                    LIGHT_GREEN,
-                );
-            }
-        } else {
-            messages.add(
-                format!("Can't equp {:?} because it;s not an Equipment.", self),
-                RED,
-            );
-        }
-    }
-
-    /// Dequip object and show a message about it
-    pub fn dequip(&mut self, messages: &mut Messages) {
-        if self.item.is_none() {
-            messages.add(
-                format!("Can't dequip {:?} because it's not an item.", self),
-                RED,
-            );
-            return;
-        };
-        if let Some(ref mut equipment) = self.equipment {
-            if equipment.equipped {
-                equipment.equipped = false;
-                messages.add(
-                    format!("Dequipped {} from {:?}.", self.name, equipment.slot),
-                    LIGHT_YELLOW,
-                );
-            }
-        } else {
-            messages.add(
-                format!("Can't dequip {:?} because it's not an Equipment.", self),
-                RED,
-            );
-        }
-    }
-
-    pub fn power(&self, game: &Game) -> i32 {
-        let base_power = self.fighter.map_or(0, |f| f.base_power);
-        let bonus: i32 = self
-            .get_all_equipped(game)
-            .iter()
-            .map(|e| e.power_bonus)
-            .sum();
-        base_power + bonus
-    }
-
-    pub fn defense(&self, game: &Game) -> i32 {
-        let base_defense = self.fighter.map_or(0, |f| f.base_defense);
-        let bonus: i32 = self
-            .get_all_equipped(game)
-            .iter()
-            .map(|e| e.defense_bonus)
-            .sum();
-        base_defense + bonus
-    }
-
-    pub fn max_hp(&self, game: &Game) -> i32 {
-        let base_max_hp = self.fighter.map_or(0, |f| f.base_max_hp);
-        let bonus: i32 = self
-            .get_all_equipped(game)
-            .iter()
-            .map(|e| e.max_hp_bonus)
-            .sum();
-        base_max_hp + bonus
-    }
-
-    pub fn get_all_equipped(&self, game: &Game) -> Vec<Equipment> {
-        if self.name == "player" {
-            game.inventory
-                .iter()
-                .filter(|item| item.equipment.map_or(false, |e| e.equipped))
-                .map(|item| item.equipment.unwrap())
-                .collect()
-        } else {
-            vec![] // other items have no equipment
-        }
-    }
-
-}
\ No newline at end of file